@@ -0,0 +1,571 @@
+//! Writing of comic archives.
+//!
+//! Archives are either zip based (cbz) or tar based (cbt). Pages can also be written to a PDF document instead.
+use std::{
+    fmt, fs,
+    io::{self, Read},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::pdf::PdfWriter;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+/// Size of the end of central directory record of a zip archive without a comment.
+const ZIP_END_SIZE: u64 = 22;
+/// Size of the local header and central directory entry of a zip entry, excluding its name which is in both.
+const ZIP_ENTRY_SIZE: u64 = 30 + 46;
+/// Allowance for extra fields, such as those of zip64, and for compressed data which is larger than its input.
+const ZIP_SLACK: u64 = 64;
+
+/// Range of seconds since the unix epoch representable by zip timestamps, 1980-01-01 00:00:00 to 2107-12-31 23:59:58.
+const ZIP_TIME_RANGE: RangeInclusive<u64> = 315_532_800..=4_354_819_198;
+
+/// Archive container formats.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ArchiveFormat {
+    Cbz,
+    Cbt,
+    Pdf,
+}
+
+impl ArchiveFormat {
+    /// Returns the file extension for the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Cbz => "cbz",
+            Self::Cbt => "cbt",
+            Self::Pdf => "pdf",
+        }
+    }
+
+    /// Clamps `mtime` to the range of modification times representable by the format.
+    ///
+    /// Returns `mtime` unchanged if it is in range.
+    pub fn clamp_mtime(self, mtime: SystemTime) -> SystemTime {
+        // Times before the unix epoch are not representable by either format.
+        let secs = mtime
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        let clamped = match self {
+            Self::Cbz => secs.clamp(*ZIP_TIME_RANGE.start(), *ZIP_TIME_RANGE.end()),
+            Self::Cbt | Self::Pdf => secs,
+        };
+
+        if clamped == secs && mtime >= SystemTime::UNIX_EPOCH {
+            mtime
+        } else {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(clamped)
+        }
+    }
+}
+
+/// Converts `mtime` to a zip timestamp in UTC.
+///
+/// Times outside of the zip range fall back to the default of 1980-01-01 00:00:00.
+fn zip_time(mtime: SystemTime) -> DateTime {
+    let mtime = OffsetDateTime::from(mtime);
+    u16::try_from(mtime.year())
+        .ok()
+        .and_then(|year| {
+            DateTime::from_date_and_time(
+                year,
+                mtime.month().into(),
+                mtime.day(),
+                mtime.hour(),
+                mtime.minute(),
+                mtime.second(),
+            )
+            .ok()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the names of all entries of the zip archive at `path`, in the order they are stored.
+///
+/// Errors are propagated with added context.
+pub fn entry_names(path: &Path) -> Result<Vec<String>> {
+    let reader = io::BufReader::new(
+        fs::File::open(path)
+            .with_context(|| format!("Failed to open {} for reading", path.display()))?,
+    );
+    let archive = ZipArchive::new(reader)
+        .with_context(|| format!("Failed to read archive {}", path.display()))?;
+
+    Ok(archive.file_names().map(str::to_string).collect())
+}
+
+/// Reads the entry `name` of the zip archive at `path`.
+///
+/// Errors are propagated with added context.
+pub fn read_entry(path: &Path, name: &str) -> Result<Vec<u8>> {
+    let reader = io::BufReader::new(
+        fs::File::open(path)
+            .with_context(|| format!("Failed to open {} for reading", path.display()))?,
+    );
+    let mut archive = ZipArchive::new(reader)
+        .with_context(|| format!("Failed to read archive {}", path.display()))?;
+    let mut data = Vec::new();
+    archive
+        .by_name(name)
+        .and_then(|mut entry| Ok(entry.read_to_end(&mut data)?))
+        .with_context(|| format!("Failed to read {name} of {}", path.display()))?;
+
+    Ok(data)
+}
+
+/// Reads every file entry of the archive at `path` in `format`, passing its name and contents to `f` in stored order.
+///
+/// Entries which can't be read, e.g. due to a checksum mismatch, are passed as an error instead, so that all of them
+/// can be reported. Fails if the archive itself can't be read, and for PDF documents which can't be read at all.
+/// Returns the number of entries read. Errors are propagated with added context.
+pub fn read_entries<F>(path: &Path, format: ArchiveFormat, mut f: F) -> Result<usize>
+where
+    F: FnMut(&str, io::Result<Vec<u8>>),
+{
+    let reader = io::BufReader::new(
+        fs::File::open(path)
+            .with_context(|| format!("Failed to open {} for reading", path.display()))?,
+    );
+    let mut count = 0;
+    match format {
+        ArchiveFormat::Cbz => {
+            let mut archive = ZipArchive::new(reader)
+                .with_context(|| format!("Failed to read archive {}", path.display()))?;
+            for i in 0..archive.len() {
+                let mut entry = archive
+                    .by_index(i)
+                    .with_context(|| format!("Failed to read entry {i} of {}", path.display()))?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let name = entry.name().to_string();
+                let mut data = Vec::new();
+                f(&name, entry.read_to_end(&mut data).map(|_| data));
+                count += 1;
+            }
+        }
+        ArchiveFormat::Cbt => {
+            let mut archive = tar::Archive::new(reader);
+            for entry in archive
+                .entries()
+                .with_context(|| format!("Failed to read archive {}", path.display()))?
+            {
+                let mut entry =
+                    entry.with_context(|| format!("Failed to read entry of {}", path.display()))?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = entry
+                    .path()
+                    .with_context(|| format!("Failed to read entry of {}", path.display()))?
+                    .to_string_lossy()
+                    .into_owned();
+                let mut data = Vec::new();
+                f(&name, entry.read_to_end(&mut data).map(|_| data));
+                count += 1;
+            }
+        }
+        ArchiveFormat::Pdf => anyhow::bail!("Reading pdf documents is not supported"),
+    }
+
+    Ok(count)
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.extension().fmt(f)
+    }
+}
+
+/// Compression methods for archive entries.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Stored,
+    Deflate,
+    Bzip2,
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the zip compression method.
+    ///
+    /// Fails if support for the method has not been compiled in.
+    fn method(self) -> Result<CompressionMethod> {
+        match self {
+            Self::Stored => Ok(CompressionMethod::Stored),
+            #[cfg(feature = "deflate")]
+            Self::Deflate => Ok(CompressionMethod::Deflated),
+            #[cfg(feature = "bzip2")]
+            Self::Bzip2 => Ok(CompressionMethod::Bzip2),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Ok(CompressionMethod::Zstd),
+            #[cfg(not(all(feature = "deflate", feature = "bzip2", feature = "zstd")))]
+            _ => anyhow::bail!("Compression method {self} is not supported by this build"),
+        }
+    }
+
+    /// Returns the range of valid compression levels, or None if the method has no levels.
+    pub fn levels(self) -> Option<RangeInclusive<i64>> {
+        match self {
+            Self::Stored => None,
+            Self::Deflate | Self::Bzip2 => Some(1..=9),
+            Self::Zstd => Some(1..=22),
+        }
+    }
+
+    /// Returns the compression level used if none is given, or None to use the default of the method.
+    ///
+    /// The default level of zstd favors speed, whereas a moderate level gives a noticeably better ratio at a similar
+    /// speed to deflate.
+    pub fn default_level(self) -> Option<i64> {
+        match self {
+            Self::Zstd => Some(10),
+            Self::Stored | Self::Deflate | Self::Bzip2 => None,
+        }
+    }
+
+    /// Returns zip file options using this method and the given compression level.
+    ///
+    /// Falls back to `default_level` if no level is given. Fails if the method is not supported or the level is out of
+    /// range for the method.
+    pub fn options(self, level: Option<i64>) -> Result<SimpleFileOptions> {
+        let method = self.method()?;
+        let level = level.or(self.default_level());
+        if let Some(level) = level
+            && let Some(levels) = self.levels()
+            && !levels.contains(&level)
+        {
+            anyhow::bail!(
+                "Compression level {level} is out of range for {self} (expected {}-{})",
+                levels.start(),
+                levels.end()
+            );
+        }
+
+        Ok(SimpleFileOptions::default()
+            .compression_method(method)
+            .compression_level(level.filter(|_| self.levels().is_some())))
+    }
+}
+
+/// Checks whether zip entries written using `options` are compressed rather than stored.
+pub fn is_compressed(options: SimpleFileOptions) -> bool {
+    options
+        != options
+            .compression_method(CompressionMethod::Stored)
+            .compression_level(None)
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_possible_value()
+            .expect("No compression method is skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// Underlying writer of an archive.
+enum Writer {
+    Zip(Box<ZipWriter<fs::File>>),
+    Tar(tar::Builder<fs::File>),
+    Pdf(PdfWriter<io::BufWriter<fs::File>>),
+}
+
+/// Reader which optionally computes the SHA-256 digest of everything read through it.
+struct HashingReader<R> {
+    reader: R,
+    hasher: Option<Sha256>,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+/// Temporary file which is removed when dropped, unless it has been moved away.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Creates a hidden temporary file next to `path`, in the same directory so that it can be renamed to `path`.
+    ///
+    /// Errors are propagated with added context.
+    fn create(path: &Path) -> Result<(Self, fs::File)> {
+        let temp = Self(path.with_file_name(format!(
+            ".{}.tmp",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        )));
+        let file = fs::File::create(&temp.0)
+            .with_context(|| format!("Failed to create file {}", temp.0.display()))?;
+
+        Ok((temp, file))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Archive being written.
+pub struct Archive {
+    path: PathBuf,
+    writer: Writer,
+    /// Temporary file the archive is written to, which replaces `path` once finished.
+    temp: TempFile,
+    /// Names and SHA-256 digests of the entries written so far, if recorded.
+    digests: Option<Vec<(String, [u8; 32])>>,
+    /// Upper bound of the size of the archive if it were finished now, only tracked for zip archives.
+    estimated_size: u64,
+}
+
+impl Archive {
+    /// Creates a new archive at `path`.
+    ///
+    /// The archive is written to a temporary file next to `path` which only replaces any existing file once finished,
+    /// so that `path` never holds a partially written archive. Errors are propagated with added context.
+    pub fn create<P>(path: P, format: ArchiveFormat) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let (temp, file) = TempFile::create(&path)?;
+        let writer = match format {
+            ArchiveFormat::Cbz => Writer::Zip(Box::new(ZipWriter::new(file))),
+            ArchiveFormat::Cbt => Writer::Tar(tar::Builder::new(file)),
+            ArchiveFormat::Pdf => Writer::Pdf(
+                PdfWriter::new(io::BufWriter::new(file))
+                    .with_context(|| format!("Failed to write to {}", path.display()))?,
+            ),
+        };
+
+        Ok(Self {
+            path,
+            writer,
+            temp,
+            digests: None,
+            estimated_size: ZIP_END_SIZE,
+        })
+    }
+
+    /// Creates a new zip archive replacing the existing one at `path`, starting out with all of its entries.
+    ///
+    /// The existing entries are copied without recompressing them, except for the entry named like `replaced`, if
+    /// provided, whose contents are replaced while keeping its position, compression and modification time. Like with
+    /// `create` the existing archive is only replaced once finished, so that it is left intact if anything fails.
+    /// Errors are propagated with added context.
+    pub fn append<P>(path: P, replaced: Option<(&str, &[u8])>) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let reader = io::BufReader::new(
+            fs::File::open(&path)
+                .with_context(|| format!("Failed to open {} for reading", path.display()))?,
+        );
+        let mut existing = ZipArchive::new(reader)
+            .with_context(|| format!("Failed to read archive {}", path.display()))?;
+
+        let (temp, file) = TempFile::create(&path)?;
+        let mut writer = ZipWriter::new(file);
+        let context = || format!("Failed to copy the entries of {}", path.display());
+        match replaced {
+            None => writer.merge_archive(existing).with_context(context)?,
+            Some((name, data)) => {
+                for i in 0..existing.len() {
+                    let entry = existing.by_index_raw(i).with_context(context)?;
+                    if entry.name() != name {
+                        writer.raw_copy_file(entry).with_context(context)?;
+                        continue;
+                    }
+                    let mut options =
+                        SimpleFileOptions::default().compression_method(entry.compression());
+                    if let Some(mtime) = entry.last_modified() {
+                        options = options.last_modified_time(mtime);
+                    }
+                    drop(entry);
+                    writer
+                        .start_file(name, options)
+                        .with_context(|| format!("Failed to add {name} to {}", path.display()))?;
+                    io::copy(&mut &*data, &mut writer)
+                        .with_context(|| format!("Failed to write {name} to {}", path.display()))?;
+                }
+            }
+        }
+
+        Ok(Self {
+            path,
+            writer: Writer::Zip(Box::new(writer)),
+            temp,
+            digests: None,
+            estimated_size: ZIP_END_SIZE,
+        })
+    }
+
+    /// Returns an upper bound of the size of the zip archive if it were finished now.
+    ///
+    /// Entries copied from an existing archive when appending are not included.
+    pub fn estimated_size(&self) -> u64 {
+        self.estimated_size
+    }
+
+    /// Returns an upper bound of how much adding an entry named `name` with `size` bytes grows a zip archive.
+    ///
+    /// Stored entries grow the archive by exactly their headers and contents, whereas compressed ones are assumed to
+    /// grow it by at most a percent more than their contents.
+    pub fn entry_size(name: &str, size: u64, compress: bool) -> u64 {
+        let size = if compress { size + size / 100 } else { size };
+        ZIP_ENTRY_SIZE + 2 * name.len() as u64 + ZIP_SLACK + size
+    }
+
+    /// Changes the path the archive is moved to once finished.
+    pub fn set_path(&mut self, path: PathBuf) {
+        self.path = path;
+    }
+
+    /// Starts recording the SHA-256 digests of all entries added from now on.
+    ///
+    /// Digests are computed while the entries are written, so that their contents don't have to be read twice.
+    pub fn record_digests(&mut self) {
+        self.digests.get_or_insert_with(Vec::new);
+    }
+
+    /// Stops recording digests and returns the names and digests of all entries recorded, in the order added.
+    pub fn take_digests(&mut self) -> Vec<(String, [u8; 32])> {
+        self.digests.take().unwrap_or_default()
+    }
+
+    /// Adds an entry named `name` with contents `buf` to the archive.
+    ///
+    /// Zip entries are written using `options`, which are ignored for tar archives and PDF documents, and names which
+    /// are not ASCII are marked as UTF-8 by the zip writer. The entry is given the modification time `mtime` if
+    /// provided, which should be clamped using `ArchiveFormat::clamp_mtime`. Errors are propagated with added context.
+    pub fn add(
+        &mut self,
+        name: &str,
+        buf: &[u8],
+        options: SimpleFileOptions,
+        mtime: Option<SystemTime>,
+    ) -> Result<()> {
+        self.add_reader(name, buf, buf.len() as u64, options, mtime)
+    }
+
+    /// Adds an entry named `name` with the contents of the file at `file` to the archive.
+    ///
+    /// The file is streamed into the archive instead of being read into memory first. Otherwise behaves like `add`.
+    /// Returns the size of the file.
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        file: &Path,
+        options: SimpleFileOptions,
+        mtime: Option<SystemTime>,
+    ) -> Result<u64> {
+        let reader = fs::File::open(file)
+            .with_context(|| format!("Failed to open {} for reading", file.display()))?;
+        let size = reader
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of {}", file.display()))?
+            .len();
+        self.add_reader(name, io::BufReader::new(reader), size, options, mtime)?;
+
+        Ok(size)
+    }
+
+    /// Adds an entry named `name` with `size` bytes read from `reader` to the archive.
+    ///
+    /// The digest of the entry is recorded if `record_digests` has been called. PDF documents have no entries, instead
+    /// the contents are added as a page, which fails unless they are an image.
+    fn add_reader<R>(
+        &mut self,
+        name: &str,
+        reader: R,
+        size: u64,
+        options: SimpleFileOptions,
+        mtime: Option<SystemTime>,
+    ) -> Result<()>
+    where
+        R: Read,
+    {
+        let path = &self.path;
+        let mut reader = HashingReader {
+            reader,
+            hasher: self.digests.as_ref().map(|_| Sha256::new()),
+        };
+        match &mut self.writer {
+            Writer::Zip(writer) => {
+                let mut options = options;
+                if let Some(mtime) = mtime {
+                    options = options.last_modified_time(zip_time(mtime));
+                }
+                writer
+                    .start_file(name, options)
+                    .with_context(|| format!("Failed to add {name} to {}", path.display()))?;
+                io::copy(&mut reader, writer)
+                    .with_context(|| format!("Failed to write {name} to {}", path.display()))?;
+                self.estimated_size += Self::entry_size(name, size, is_compressed(options));
+            }
+            Writer::Tar(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(size);
+                header.set_mode(0o644);
+                if let Some(mtime) = mtime {
+                    header.set_mtime(
+                        mtime
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .map_or(0, |duration| duration.as_secs()),
+                    );
+                }
+                builder
+                    .append_data(&mut header, name, &mut reader)
+                    .with_context(|| format!("Failed to add {name} to {}", path.display()))?;
+            }
+            Writer::Pdf(pdf) => {
+                let mut buf = Vec::new();
+                reader
+                    .read_to_end(&mut buf)
+                    .with_context(|| format!("Failed to read {name}"))?;
+                pdf.add_image(&buf)
+                    .with_context(|| format!("Failed to add {name} to {}", path.display()))?;
+            }
+        }
+        if let (Some(digests), Some(hasher)) = (&mut self.digests, reader.hasher) {
+            digests.push((name.to_string(), hasher.finalize().into()));
+        }
+
+        Ok(())
+    }
+
+    /// Finalizes the archive.
+    ///
+    /// The temporary file is moved into place once the archive is complete. Errors are propagated with added context.
+    pub fn finish(self) -> Result<()> {
+        let Self {
+            path, writer, temp, ..
+        } = self;
+        match writer {
+            Writer::Zip(writer) => writer.finish().map(|_| ()).map_err(anyhow::Error::from),
+            Writer::Tar(mut builder) => builder.finish().map_err(anyhow::Error::from),
+            Writer::Pdf(pdf) => pdf.finish().map(|_| ()).map_err(anyhow::Error::from),
+        }
+        .with_context(|| format!("Failed to finalize {}", path.display()))?;
+        fs::rename(&temp.0, &path)
+            .with_context(|| format!("Failed to move {} into place", path.display()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! Generation of ComicInfo.xml metadata files.
+//!
+//! Only a subset of the ComicInfo schema is supported. Elements are written in schema order. Existing metadata is only
+//! edited textually, to update its page count.
+use std::{fmt::Write, sync::LazyLock};
+
+use regex::Regex;
+
+/// File name of the metadata file inside an archive.
+pub const FILE_NAME: &str = "ComicInfo.xml";
+
+/// Matches a publication year in parentheses, e.g. `(2021)`.
+static YEAR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\((\d{4})\)").expect("Year pattern is valid"));
+/// Matches a volume number, e.g. `v03`, `Vol. 3` or `Volume 3`.
+static VOLUME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(?:v|vol\.?\s*|volume\s+)(\d+)\b").expect("Volume pattern is valid")
+});
+/// Matches an issue number, e.g. `#12`.
+static NUMBER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"#(\d+(?:\.\d+)?)").expect("Number pattern is valid"));
+/// Matches any remaining bracketed tags, e.g. `(Digital)` or `[Group]`.
+static TAGS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\([^)]*\)|\[[^\]]*\]").expect("Tags pattern is valid"));
+/// Matches the page count element, e.g. `<PageCount>12</PageCount>`.
+static PAGE_COUNT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"<PageCount>\s*\d*\s*</PageCount>").expect("Page count pattern is valid")
+});
+/// Matches the start of a page entry.
+static PAGE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<Page\s").expect("Page pattern is valid"));
+/// Matches the end of the list of pages.
+static PAGES_END: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"([ \t]*)</Pages>").expect("Pages end pattern is valid"));
+
+/// Updates existing metadata for pages appended after its first `existing` pages, giving `total` pages in all.
+///
+/// The page count is replaced if present. If every existing page is listed, an entry is added for each appended page,
+/// so that the list stays complete. Everything else is kept as it is.
+pub fn update_page_count(xml: &str, existing: usize, total: usize) -> String {
+    let xml = PAGE_COUNT.replace(xml, format!("<PageCount>{total}</PageCount>"));
+    if PAGE.find_iter(&xml).count() < existing {
+        return xml.into_owned();
+    }
+    PAGES_END
+        .replace(&xml, |captures: &regex::Captures| {
+            let indent = &captures[1];
+            let mut pages = String::new();
+            for page in existing..total {
+                // Writing to a String never fails.
+                let _ = writeln!(pages, "{indent}  <Page Image=\"{page}\" />");
+            }
+            format!("{pages}{indent}</Pages>")
+        })
+        .into_owned()
+}
+
+/// Comic metadata.
+///
+/// All fields are optional and only set fields are written.
+#[derive(Debug, Default)]
+pub struct ComicInfo {
+    pub title: Option<String>,
+    pub series: Option<String>,
+    pub number: Option<String>,
+    pub volume: Option<u32>,
+    pub year: Option<u16>,
+    pub writer: Option<String>,
+    pub page_count: Option<usize>,
+    /// Index of the page designated as front cover.
+    pub cover: Option<usize>,
+    /// Indices of the first pages of chapters, along with the chapter names, in page order.
+    pub bookmarks: Vec<(usize, String)>,
+}
+
+/// Escapes text for use in XML element content.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl ComicInfo {
+    /// Parses metadata from a folder name such as `My Series v03 #12 (2021)`.
+    ///
+    /// The volume, issue number and year are recognized by common patterns and the series is whatever remains once
+    /// those and any other bracketed tags are removed. Fields which can't be found are left empty.
+    pub fn from_folder_name(name: &str) -> Self {
+        let capture = |regex: &Regex| {
+            regex
+                .captures(name)
+                .and_then(|captures| captures.get(1))
+                .map(|m| m.as_str().to_string())
+        };
+        let mut series = name.to_string();
+        for regex in [&*YEAR, &*VOLUME, &*NUMBER, &*TAGS] {
+            series = regex.replace_all(&series, " ").into_owned();
+        }
+        let series = series
+            .replace('_', " ")
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .trim_matches(|c: char| c == '-' || c == '.' || c.is_whitespace())
+            .to_string();
+
+        Self {
+            series: (!series.is_empty()).then_some(series),
+            number: capture(&NUMBER),
+            volume: capture(&VOLUME).and_then(|volume| volume.parse().ok()),
+            year: capture(&YEAR).and_then(|year| year.parse().ok()),
+            ..Self::default()
+        }
+    }
+
+    /// Returns true if no metadata has been provided by the user.
+    ///
+    /// `page_count`, `cover` and `bookmarks` are not considered as they are derived from the archive contents.
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.series.is_none()
+            && self.number.is_none()
+            && self.volume.is_none()
+            && self.year.is_none()
+            && self.writer.is_none()
+    }
+
+    /// Serializes the metadata to XML.
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+            "<ComicInfo xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" ",
+            "xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">\n",
+        ));
+        let mut element = |name: &str, value: Option<String>| {
+            if let Some(value) = value {
+                // Writing to a String never fails.
+                let _ = writeln!(xml, "  <{name}>{}</{name}>", escape(&value));
+            }
+        };
+        element("Title", self.title.clone());
+        element("Series", self.series.clone());
+        element("Number", self.number.clone());
+        element("Volume", self.volume.map(|volume| volume.to_string()));
+        element("Year", self.year.map(|year| year.to_string()));
+        element("Writer", self.writer.clone());
+        element("PageCount", self.page_count.map(|count| count.to_string()));
+        // A cover which is also the first page of a chapter gets both attributes on a single element.
+        let mut pages = self
+            .bookmarks
+            .iter()
+            .map(|(page, name)| (*page, Some(name.as_str())))
+            .collect::<Vec<_>>();
+        if let Some(cover) = self.cover
+            && !pages.iter().any(|&(page, _)| page == cover)
+        {
+            pages.push((cover, None));
+            pages.sort_by_key(|&(page, _)| page);
+        }
+        if !pages.is_empty() {
+            xml.push_str("  <Pages>\n");
+            for (page, bookmark) in pages {
+                let _ = write!(xml, "    <Page Image=\"{page}\"");
+                if self.cover == Some(page) {
+                    xml.push_str(" Type=\"FrontCover\"");
+                }
+                if let Some(bookmark) = bookmark {
+                    let _ = write!(xml, " Bookmark=\"{}\"", escape(bookmark));
+                }
+                xml.push_str(" />\n");
+            }
+            xml.push_str("  </Pages>\n");
+        }
+        xml.push_str("</ComicInfo>\n");
+        xml
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appended_pages_are_counted_and_listed() {
+        let info = ComicInfo {
+            page_count: Some(2),
+            bookmarks: vec![(0, "a".to_string()), (1, "b".to_string())],
+            ..ComicInfo::default()
+        };
+        let xml = update_page_count(&info.to_xml(), 2, 3);
+        assert!(xml.contains("<PageCount>3</PageCount>"));
+        assert!(xml.contains(concat!(
+            "    <Page Image=\"1\" Bookmark=\"b\" />\n",
+            "    <Page Image=\"2\" />\n",
+            "  </Pages>\n"
+        )));
+    }
+
+    #[test]
+    fn incomplete_page_lists_are_kept() {
+        let info = ComicInfo {
+            page_count: Some(2),
+            cover: Some(0),
+            ..ComicInfo::default()
+        };
+        let xml = update_page_count(&info.to_xml(), 2, 3);
+        assert_eq!(xml, info.to_xml().replace("<PageCount>2<", "<PageCount>3<"));
+    }
+}
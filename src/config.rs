@@ -0,0 +1,122 @@
+//! Configuration files.
+//!
+//! A configuration file is written in TOML and maps categories of archive entries to the compression method and level
+//! used for them, e.g.
+//!
+//! ```toml
+//! method = "deflate"
+//!
+//! [jpeg]
+//! method = "stored"
+//!
+//! [png]
+//! method = "zstd"
+//! level = 19
+//! ```
+//!
+//! The top-level `method` and `level` apply to entries of categories without a rule of their own. Configurations are
+//! validated when loaded, so that unknown categories and invalid levels are rejected before any directory is processed.
+use std::fs;
+
+use crate::archive::Compression;
+use anyhow::Result;
+use image::ImageFormat;
+use serde::Deserialize;
+use zip::write::SimpleFileOptions;
+
+/// Compression of the entries of one category.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub method: Compression,
+    pub level: Option<i64>,
+}
+
+impl Rule {
+    /// Returns zip file options using the method and level of the rule.
+    ///
+    /// Fails like `Compression::options`, which can't happen for rules of a loaded configuration.
+    pub fn options(self) -> Result<SimpleFileOptions> {
+        self.method.options(self.level)
+    }
+}
+
+/// Parsed configuration file.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Method of entries without a rule, unless given by `--compression`.
+    pub method: Option<Compression>,
+    /// Level of entries without a rule, unless given by `--level`.
+    pub level: Option<i64>,
+    pub jpeg: Option<Rule>,
+    pub png: Option<Rule>,
+    pub gif: Option<Rule>,
+    pub webp: Option<Rule>,
+    pub avif: Option<Rule>,
+    pub tiff: Option<Rule>,
+    pub bmp: Option<Rule>,
+    /// ComicInfo.xml, whether generated or added as-is.
+    pub comic_info: Option<Rule>,
+    /// Manifest of the SHA-256 digests of the entries.
+    pub manifest: Option<Rule>,
+    /// Table of contents of the chapters.
+    pub toc: Option<Rule>,
+    /// Any other file added as-is, such as files named by `--include-as-is` and included non-images.
+    pub other: Option<Rule>,
+}
+
+impl Config {
+    /// Reads and parses the configuration file at `path`.
+    ///
+    /// Fails on unknown keys, including unknown categories, and if a method is not supported by this build or a level
+    /// is out of range for its method. Errors are described in full, as they are shown as invalid values of `--config`.
+    pub fn load(path: &str) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read file: {e}"))?;
+        let config: Self =
+            toml::from_str(&text).map_err(|e| anyhow::anyhow!("{}", e.to_string().trim_end()))?;
+        if let Some(method) = config.method {
+            method.options(config.level)?;
+        }
+        for (name, rule) in config.rules() {
+            if let Some(rule) = rule {
+                rule.options()
+                    .map_err(|e| anyhow::anyhow!("Invalid rule for {name}: {e}"))?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the categories and their rules, named as in configuration files.
+    fn rules(&self) -> [(&'static str, Option<Rule>); 11] {
+        [
+            ("jpeg", self.jpeg),
+            ("png", self.png),
+            ("gif", self.gif),
+            ("webp", self.webp),
+            ("avif", self.avif),
+            ("tiff", self.tiff),
+            ("bmp", self.bmp),
+            ("comic-info", self.comic_info),
+            ("manifest", self.manifest),
+            ("toc", self.toc),
+            ("other", self.other),
+        ]
+    }
+
+    /// Returns the rule for pages in `format`, if any.
+    pub fn page(&self, format: ImageFormat) -> Option<Rule> {
+        match format {
+            ImageFormat::Jpeg => self.jpeg,
+            ImageFormat::Png => self.png,
+            ImageFormat::Gif => self.gif,
+            ImageFormat::WebP => self.webp,
+            ImageFormat::Avif => self.avif,
+            ImageFormat::Tiff => self.tiff,
+            ImageFormat::Bmp => self.bmp,
+            _ => None,
+        }
+    }
+}
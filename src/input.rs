@@ -0,0 +1,382 @@
+//! Reading of the files to be packed.
+//!
+//! Files are either read from a directory, from the entries of an existing zip based archive, from the entries of all
+//! archives inside a directory, or from the images and directories listed in a text file. Archives are read into memory
+//! as a whole, and their entries are addressed by the path of the archive joined with the entry name, so that they can
+//! be handled just like the files of a directory. Merged archives thereby appear as subdirectories of their directory.
+//! Files outside the archive, such as a separate cover, are read from the file system.
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, BufRead, Read, Seek},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use zip::ZipArchive;
+
+/// Extensions of archives which can be read.
+const EXTENSIONS: [&str; 2] = ["cbz", "zip"];
+
+/// Reader over the contents of a file.
+pub trait Reader: BufRead + Seek {}
+
+impl<R: BufRead + Seek> Reader for R {}
+
+/// File read from an archive.
+pub struct File {
+    data: Vec<u8>,
+    modified: Option<SystemTime>,
+}
+
+/// Source of the files to be packed.
+pub enum Input {
+    /// A directory.
+    Dir(PathBuf),
+    /// An archive, or a directory of archives to be merged, along with all files and directories inside them.
+    Archive {
+        path: PathBuf,
+        files: HashMap<PathBuf, File>,
+        dirs: HashSet<PathBuf>,
+        /// Paths of the merged archives, sorted, which is empty unless merging.
+        merged: Vec<PathBuf>,
+    },
+    /// A list file, along with the paths listed in it, in order.
+    List {
+        path: PathBuf,
+        entries: Vec<PathBuf>,
+    },
+}
+
+/// Converts a zip timestamp in UTC to a system time.
+///
+/// Returns None if the timestamp is not a valid date and time.
+fn system_time(date_time: zip::DateTime) -> Option<SystemTime> {
+    let month = time::Month::try_from(date_time.month()).ok()?;
+    let date =
+        time::Date::from_calendar_date(date_time.year().into(), month, date_time.day()).ok()?;
+    let time =
+        time::Time::from_hms(date_time.hour(), date_time.minute(), date_time.second()).ok()?;
+
+    Some(time::PrimitiveDateTime::new(date, time).assume_utc().into())
+}
+
+/// Reads all entries of the archive at `path` into `files` and `dirs`.
+///
+/// Entries are addressed by `path` joined with their names, and all directories inside the archive are added to `dirs`.
+/// Entries with unsafe paths, such as those escaping the archive, are rejected. Errors are propagated with added
+/// context.
+fn read_archive(
+    path: &Path,
+    files: &mut HashMap<PathBuf, File>,
+    dirs: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    let reader = io::BufReader::new(
+        fs::File::open(path)
+            .with_context(|| format!("Failed to open {} for reading", path.display()))?,
+    );
+    let mut archive = ZipArchive::new(reader)
+        .with_context(|| format!("Failed to read archive {}", path.display()))?;
+    for i in 0..archive.len() {
+        let mut entry = archive
+            .by_index(i)
+            .with_context(|| format!("Failed to read entry {i} of {}", path.display()))?;
+        let name = entry.enclosed_name().with_context(|| {
+            format!(
+                "{} contains an entry with an unsafe path {}",
+                path.display(),
+                entry.name()
+            )
+        })?;
+        let file = path.join(name);
+        dirs.extend(
+            file.ancestors()
+                .skip(1)
+                .take_while(|&dir| dir != path)
+                .map(Path::to_path_buf),
+        );
+        if entry.is_dir() {
+            dirs.insert(file);
+            continue;
+        }
+
+        let mut data = Vec::new();
+        entry
+            .read_to_end(&mut data)
+            .with_context(|| format!("Failed to read file {}", file.display()))?;
+        let modified = entry.last_modified().and_then(system_time);
+        files.insert(file, File { data, modified });
+    }
+
+    Ok(())
+}
+
+impl Input {
+    /// Checks if a path is an archive which can be read.
+    ///
+    /// Archives are files with a `.cbz` or `.zip` extension, ignoring case.
+    pub fn is_archive(path: &Path) -> bool {
+        path.is_file()
+            && path.extension().is_some_and(|extension| {
+                EXTENSIONS
+                    .iter()
+                    .any(|ext| extension.eq_ignore_ascii_case(ext))
+            })
+    }
+
+    /// Opens `path` for reading.
+    ///
+    /// If `path` is an archive as checked by `is_archive`, all its entries are read into memory as described in
+    /// `read_archive`. Errors are propagated with added context.
+    pub fn open(path: &Path) -> Result<Self> {
+        if !Self::is_archive(path) {
+            return Ok(Self::Dir(path.to_path_buf()));
+        }
+
+        let mut files = HashMap::new();
+        let mut dirs = HashSet::new();
+        read_archive(path, &mut files, &mut dirs)?;
+
+        Ok(Self::Archive {
+            path: path.to_path_buf(),
+            files,
+            dirs,
+            merged: Vec::new(),
+        })
+    }
+
+    /// Opens all archives directly inside the directory `path` for reading, to be merged into one.
+    ///
+    /// Archives are checked by `is_archive`. All their entries are read into memory as described in `read_archive`,
+    /// with every archive appearing as a subdirectory of `path`, so that they are ordered like subdirectories. Other
+    /// files inside `path` are left out. Fails if there are no archives. Errors are propagated with added context.
+    pub fn merge(path: &Path) -> Result<Self> {
+        let mut merged = Vec::new();
+        for entry in fs::read_dir(path)
+            .with_context(|| format!("Failed to read directory {}", path.display()))?
+        {
+            let entry = entry.with_context(|| format!("Error while reading {}", path.display()))?;
+            if Self::is_archive(&entry.path()) {
+                merged.push(entry.path());
+            }
+        }
+        if merged.is_empty() {
+            anyhow::bail!(
+                "{} doesn't contain any cbz/zip archives to merge",
+                path.display()
+            );
+        }
+        merged.sort();
+
+        let mut files = HashMap::new();
+        let mut dirs = HashSet::new();
+        for archive in &merged {
+            dirs.insert(archive.clone());
+            read_archive(archive, &mut files, &mut dirs)?;
+        }
+
+        Ok(Self::Archive {
+            path: path.to_path_buf(),
+            files,
+            dirs,
+            merged,
+        })
+    }
+
+    /// Reads the list file at `path`.
+    ///
+    /// Every line is the path of an image or directory, with relative paths being relative to the directory of the list
+    /// file. Empty lines and lines starting with `#` are skipped. Fails if the list can't be read, if it lists nothing,
+    /// or if any listed path doesn't exist, naming all missing paths. Errors are propagated with added context.
+    pub fn from_list(path: &Path) -> Result<Self> {
+        let list = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read list file {}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        let entries = list
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| base.join(line))
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            anyhow::bail!("List file {} doesn't list any paths", path.display());
+        }
+        let missing = entries
+            .iter()
+            .filter(|entry| !entry.exists())
+            .map(|entry| entry.display().to_string())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "List file {} lists {} missing paths: {}",
+                path.display(),
+                missing.len(),
+                missing.join(", ")
+            );
+        }
+
+        Ok(Self::List {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// Returns the path of the directory, archive or list file.
+    pub fn root(&self) -> &Path {
+        match self {
+            Self::Dir(path) | Self::Archive { path, .. } | Self::List { path, .. } => path,
+        }
+    }
+
+    /// Returns the listed paths if reading from a list file.
+    pub fn listed(&self) -> Option<&[PathBuf]> {
+        match self {
+            Self::List { entries, .. } => Some(entries),
+            Self::Dir(_) | Self::Archive { .. } => None,
+        }
+    }
+
+    /// Checks if `path` is directly inside the directory or archive, directly inside one of the merged archives, or
+    /// directly inside one of the listed directories.
+    pub fn is_top_level(&self, path: &Path) -> bool {
+        match self {
+            Self::Archive { merged, .. } if !merged.is_empty() => {
+                merged.iter().any(|archive| path.parent() == Some(archive))
+            }
+            Self::Dir(root) | Self::Archive { path: root, .. } => path.parent() == Some(root),
+            Self::List { entries, .. } => entries.iter().any(|entry| path.parent() == Some(entry)),
+        }
+    }
+
+    /// Checks if the files are read from an archive.
+    pub fn is_archived(&self) -> bool {
+        matches!(self, Self::Archive { .. })
+    }
+
+    /// Returns the paths of the merged archives if merging them.
+    pub fn merged(&self) -> Option<&[PathBuf]> {
+        match self {
+            Self::Archive { merged, .. } if !merged.is_empty() => Some(merged),
+            Self::Dir(_) | Self::Archive { .. } | Self::List { .. } => None,
+        }
+    }
+
+    /// Returns the paths of all files and directories directly inside `dir`, in no particular order.
+    ///
+    /// Errors are propagated with added context.
+    pub fn read_dir(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        match self {
+            Self::Dir(_) | Self::List { .. } => {
+                let mut paths = Vec::new();
+                for entry in fs::read_dir(dir)
+                    .with_context(|| format!("Failed to read directory {}", dir.display()))?
+                {
+                    let entry =
+                        entry.with_context(|| format!("Error while reading {}", dir.display()))?;
+                    paths.push(entry.path());
+                }
+                Ok(paths)
+            }
+            Self::Archive { files, dirs, .. } => Ok(files
+                .keys()
+                .chain(dirs)
+                .filter(|path| path.parent() == Some(dir))
+                .cloned()
+                .collect()),
+        }
+    }
+
+    /// Checks if `path` is a file.
+    pub fn is_file(&self, path: &Path) -> bool {
+        match self {
+            Self::Dir(_) | Self::List { .. } => path.is_file(),
+            Self::Archive { files, .. } => files.contains_key(path),
+        }
+    }
+
+    /// Checks if `path` is a directory.
+    pub fn is_dir(&self, path: &Path) -> bool {
+        match self {
+            Self::Dir(_) | Self::List { .. } => path.is_dir(),
+            Self::Archive { dirs, .. } => dirs.contains(path),
+        }
+    }
+
+    /// Checks if `path` is a symbolic link.
+    ///
+    /// Entries inside an archive are never symbolic links.
+    pub fn is_symlink(&self, path: &Path) -> bool {
+        match self {
+            Self::Dir(_) | Self::List { .. } => path.is_symlink(),
+            Self::Archive { .. } => false,
+        }
+    }
+
+    /// Returns the files of the archive if reading from one and `path` is inside it.
+    fn archived(&self, path: &Path) -> Option<&HashMap<PathBuf, File>> {
+        match self {
+            Self::Archive {
+                path: root, files, ..
+            } if path.starts_with(root) => Some(files),
+            Self::Dir(_) | Self::Archive { .. } | Self::List { .. } => None,
+        }
+    }
+
+    /// Opens the file at `path` for reading.
+    ///
+    /// Errors are propagated with added context.
+    pub fn reader(&self, path: &Path) -> Result<Box<dyn Reader + '_>> {
+        Ok(match self.archived(path) {
+            Some(_) => Box::new(io::Cursor::new(self.read(path)?)),
+            None => Box::new(io::BufReader::new(fs::File::open(path).with_context(
+                || format!("Failed to open {} for reading", path.display()),
+            )?)),
+        })
+    }
+
+    /// Reads the contents of the file at `path`.
+    ///
+    /// Files inside an archive are borrowed rather than copied. Errors are propagated with added context.
+    pub fn read(&self, path: &Path) -> Result<Cow<'_, [u8]>> {
+        match self.archived(path) {
+            None => Ok(Cow::Owned(fs::read(path).with_context(|| {
+                format!("Failed to read file {}", path.display())
+            })?)),
+            Some(files) => files
+                .get(path)
+                .map(|file| Cow::Borrowed(file.data.as_slice()))
+                .with_context(|| format!("Failed to read file {}", path.display())),
+        }
+    }
+
+    /// Returns the size of the file at `path` in bytes.
+    ///
+    /// Errors are propagated with added context.
+    pub fn size(&self, path: &Path) -> Result<u64> {
+        match self.archived(path) {
+            None => Ok(fs::metadata(path)
+                .with_context(|| format!("Failed to read metadata of {}", path.display()))?
+                .len()),
+            Some(files) => files
+                .get(path)
+                .map(|file| file.data.len() as u64)
+                .with_context(|| format!("Failed to read file {}", path.display())),
+        }
+    }
+
+    /// Returns the modification time of the file at `path`.
+    ///
+    /// Fails if the modification time is unavailable, which is also the case for archive entries without a valid
+    /// timestamp.
+    pub fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        match self.archived(path) {
+            None => fs::metadata(path).and_then(|metadata| metadata.modified()),
+            Some(files) => files
+                .get(path)
+                .and_then(|file| file.modified)
+                .ok_or_else(|| io::Error::other("no valid timestamp")),
+        }
+    }
+}
@@ -0,0 +1,4339 @@
+//! Creates comic archives from directories of images after checking for valid image files.
+//!
+//! This is the library behind the `makecbz` command line tool. A directory, archive or list file is opened as an
+//! `Input`, checked using `check_dir` and packed using `create_cbz`, as configured by `CbzOptions`. Messages are
+//! reported through an `Output`, which can capture them instead of printing them, and the outcome is recorded in a
+//! `Summary`.
+pub mod archive;
+pub mod comic_info;
+pub mod config;
+pub mod input;
+pub mod log;
+pub mod output;
+mod pdf;
+pub mod summary;
+pub mod template;
+
+use std::{
+    borrow::Cow,
+    cmp::{Ordering, max},
+    collections::{HashMap, HashSet, hash_map},
+    fmt::Write,
+    fs, io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc,
+    thread,
+    time::SystemTime,
+};
+
+use crate::{
+    archive::{Archive, ArchiveFormat, Compression, is_compressed},
+    comic_info::ComicInfo,
+    config::{Config, Rule},
+    input::Input,
+    output::Output,
+    summary::{FileDetails, Status, Summary},
+    template::NameTemplate,
+};
+use anyhow::{Context, Result};
+use clap::{ArgGroup, Parser, ValueEnum};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use image::{
+    AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Rgba,
+    codecs::{gif::GifDecoder, webp::WebPDecoder},
+    imageops::FilterType,
+    metadata::Orientation,
+};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use jpeg_encoder::SamplingFactor;
+use rayon::iter::{IntoParallelIterator, ParallelBridge, ParallelIterator};
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+use zip::write::SimpleFileOptions;
+
+/// Supported image formats.
+pub const FORMATS: [ImageFormat; 6] = [
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+    ImageFormat::Tiff,
+    ImageFormat::Bmp,
+];
+/// Supported image formats which most readers can't display.
+const UNREADABLE_FORMATS: [ImageFormat; 2] = [ImageFormat::Tiff, ImageFormat::Bmp];
+/// Excluded file names.
+const EXCLUDED_FILES: [&str; 1] = ["ComicInfo.xml"];
+/// File names of system files which are skipped along with hidden files.
+const SYSTEM_FILES: [&str; 3] = ["Thumbs.db", "desktop.ini", "ehthumbs.db"];
+/// Name of the file containing ignore patterns for a directory.
+const IGNORE_FILE: &str = ".makecbzignore";
+/// Name of the checksum manifest written into archives.
+const MANIFEST_FILE: &str = "makecbz-manifest.txt";
+/// Name of the table of contents written into archives.
+const TOC_FILE: &str = "toc.txt";
+/// Characters replaced by `sanitize_name`, as they are reserved on some platforms or confuse some readers.
+const RESERVED_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+/// Maximum fraction of the width or height trimmed from each edge of an image.
+const MAX_TRIM_FRACTION: f64 = 0.25;
+/// Minimum fraction of the width and height of an image that must remain as content for it to be trimmed.
+const MIN_CONTENT_FRACTION: f64 = 0.02;
+/// Maximum relative deviation of the aspect ratio of an image from the median before it is considered unusual.
+const MAX_ASPECT_DEVIATION: f64 = 0.2;
+/// Number of files above which a progress bar is shown while checking them, even if they are not decoded.
+const CHECK_PROGRESS_THRESHOLD: usize = 200;
+
+/// Formats images can be converted to.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConvertFormat {
+    Webp,
+    Avif,
+    Jpeg,
+}
+
+impl ConvertFormat {
+    /// Returns the corresponding image format.
+    pub fn format(self) -> ImageFormat {
+        match self {
+            Self::Webp => ImageFormat::WebP,
+            Self::Avif => ImageFormat::Avif,
+            Self::Jpeg => ImageFormat::Jpeg,
+        }
+    }
+
+    /// Checks whether support for encoding the format has been compiled in.
+    pub fn is_supported(self) -> bool {
+        self.format().writing_enabled()
+    }
+}
+
+/// Chroma subsampling of encoded JPEG images.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JpegSubsampling {
+    #[value(name = "444")]
+    S444,
+    #[value(name = "422")]
+    S422,
+    #[value(name = "420")]
+    S420,
+}
+
+impl JpegSubsampling {
+    /// Returns the sampling factor of the encoder.
+    fn sampling_factor(self) -> SamplingFactor {
+        match self {
+            Self::S444 => SamplingFactor::R_4_4_4,
+            Self::S422 => SamplingFactor::R_4_2_2,
+            Self::S420 => SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+/// Filters used for resizing images.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    /// Returns the corresponding filter type.
+    fn filter_type(self) -> FilterType {
+        match self {
+            Self::Nearest => FilterType::Nearest,
+            Self::Triangle => FilterType::Triangle,
+            Self::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Reading directions used for ordering split spreads.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum SplitDirection {
+    Ltr,
+    Rtl,
+}
+
+/// Dithering applied when reducing the colors of images.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+}
+
+/// Ways of dealing with animated images.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnimatedPolicy {
+    /// Treat them as non-images.
+    Reject,
+    /// Only keep their first frame.
+    #[value(alias = "flatten")]
+    FirstFrame,
+    /// Fail the directory.
+    Error,
+}
+
+/// Criteria pages are sorted by.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortBy {
+    Natural,
+    #[value(alias = "name")]
+    Lexical,
+    Size,
+    Mtime,
+    ExifDate,
+}
+
+/// Options for packing a directory, which are also the command line arguments affecting how directories are packed.
+///
+/// `CbzOptions::default` returns the defaults of the command line arguments. Mutually exclusive alternatives, such as
+/// the ways of removing the originals or of placing the output, are grouped so that clap rejects combining them,
+/// whereas combinations depending on values, such as `--format pdf` with `--manifest`, are rejected by `validate`.
+#[derive(clap::Args, Debug)]
+#[command(
+    group(ArgGroup::new("removal").args(["delete", "trash"])),
+    group(ArgGroup::new("destination").args(["output", "out_dir"])),
+    group(ArgGroup::new("grayscale_mode").args(["grayscale", "force_grayscale"]))
+)]
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "Bools represent command line switches"
+)]
+pub struct CbzOptions {
+    #[arg(short, long, help = "Don't rename files")]
+    pub no_rename: bool,
+    #[arg(
+        long,
+        requires = "no_rename",
+        help = "Replace problematic characters in kept file names (requires --no-rename)"
+    )]
+    pub sanitize_names: bool,
+    #[arg(
+        long,
+        requires = "no_rename",
+        help = "Correct extensions of kept file names not matching the image format (requires --no-rename)"
+    )]
+    pub fix_extensions: bool,
+    #[arg(
+        long,
+        requires = "no_rename",
+        help = "Add numeric suffixes to kept file names which would collide (requires --no-rename)"
+    )]
+    pub unique_names: bool,
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with = "no_rename",
+        help = "Name pages using a template, e.g. \"{series} {n:03}.{ext}\""
+    )]
+    pub name_template: Option<NameTemplate>,
+    #[arg(long, value_name = "N", help = "Zero-pad page numbers to this width")]
+    pub pad_width: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        help = "Number of the first page"
+    )]
+    pub start_number: usize,
+    #[arg(short, long, help = "Delete original files")]
+    pub delete: bool,
+    #[arg(
+        long,
+        requires = "delete",
+        help = "Delete without asking for confirmation"
+    )]
+    pub yes: bool,
+    #[arg(
+        long,
+        help = "Move original files to the trash instead of deleting them"
+    )]
+    pub trash: bool,
+    #[arg(short, long, help = "Verify image data")]
+    pub verify: bool,
+    #[arg(
+        long,
+        requires = "verify",
+        help = "Warn about images with unusual aspect ratios (requires --verify)"
+    )]
+    pub check_dimensions: bool,
+    #[arg(
+        long,
+        help = "Fail instead of warning about anomalies, such as unreadable files or animated images"
+    )]
+    pub strict: bool,
+    #[arg(
+        long,
+        help = "Reopen the finished archive and decode every image before deleting anything"
+    )]
+    pub verify_output: bool,
+    #[arg(
+        long,
+        help = "Skip images which fail to be read or transformed while writing instead of failing the directory"
+    )]
+    pub continue_on_error: bool,
+    #[arg(
+        long,
+        value_name = "PX",
+        help = "Drop images whose shorter side is smaller than this, e.g. spacers and logos"
+    )]
+    pub min_dimension: Option<u32>,
+    #[arg(long, help = "Preserve modification times of files")]
+    pub preserve_mtime: bool,
+    #[arg(long, help = "Overwrite output file if it exists")]
+    pub overwrite: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["overwrite", "append"],
+        help = "Overwrite output file only if the source has changed since, skipping it otherwise"
+    )]
+    pub overwrite_newer: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["manifest", "overwrite"],
+        help = "Append pages to the output cbz if it exists, numbering them after its pages"
+    )]
+    pub append: bool,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        conflicts_with_all = ["append", "manifest"],
+        help = "Split the cbz into numbered parts of at most this size, e.g. 200M, with metadata describing the whole set in the first part"
+    )]
+    pub max_archive_size: Option<u64>,
+    #[arg(
+        long,
+        conflicts_with = "sort_by",
+        help = "Sort files lexically instead of naturally"
+    )]
+    pub lexical_sort: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SortBy::Natural,
+        help = "Sort pages by (natural: file names with numbers by value, lexical: file names in byte order)"
+    )]
+    pub sort_by: SortBy,
+    #[arg(long, help = "Reverse the order of pages")]
+    pub reverse: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "sort_by",
+        help = "Order pages as listed in a file, one file name per line (only valid with a single directory)"
+    )]
+    pub page_order_file: Option<PathBuf>,
+    #[arg(
+        long,
+        requires = "page_order_file",
+        help = "Add images missing from --page-order-file after the listed ones instead of aborting"
+    )]
+    pub append_unlisted: bool,
+    #[arg(short, long, help = "Scan subdirectories recursively")]
+    pub recursive: bool,
+    #[arg(
+        long,
+        requires = "recursive",
+        help = "Descend into symlinked subdirectories (requires --recursive)"
+    )]
+    pub follow_symlinks: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["delete", "trash", "rename_only"],
+        help = "Merge the cbz/zip archives inside each directory into one, e.g. chapters into a volume"
+    )]
+    pub merge_archives: bool,
+    #[arg(long, help = "Don't skip hidden and system files")]
+    pub no_skip_hidden: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Read ignore patterns from this file instead of .makecbzignore"
+    )]
+    pub ignore_file: Option<PathBuf>,
+    #[arg(long, help = "Only add the first of any identical images")]
+    pub dedupe: bool,
+    #[arg(
+        long,
+        help = "Only add the first of any run of visually near-identical adjacent images"
+    )]
+    pub dedupe_adjacent: bool,
+    #[arg(
+        long,
+        value_name = "BITS",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u32).range(0..=64),
+        requires = "dedupe_adjacent",
+        help = "Maximum number of differing perceptual hash bits for --dedupe-adjacent, out of 64"
+    )]
+    pub phash_threshold: u32,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Add files with this name as-is, like ComicInfo.xml (can be repeated)"
+    )]
+    pub include_as_is: Vec<String>,
+    #[arg(long, help = "Don't add ComicInfo.xml as-is")]
+    pub no_exclude: bool,
+    #[arg(
+        long,
+        help = "Add an existing ComicInfo.xml before the pages instead of after them"
+    )]
+    pub comic_info_first: bool,
+    #[arg(
+        long,
+        conflicts_with = "no_exclude",
+        help = "Add ComicInfo.xml as-is regardless of the case of its name, named exactly ComicInfo.xml"
+    )]
+    pub normalize_comic_info: bool,
+    #[arg(long, help = "Leave non-images out of the archive instead of aborting")]
+    pub skip_non_images: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "first-frame",
+        help = "What to do with animated GIF and WebP images"
+    )]
+    pub animated: AnimatedPolicy,
+    #[arg(
+        long,
+        conflicts_with = "animated",
+        help = "Add every frame of animated GIF and WebP images as a separate page"
+    )]
+    pub explode_frames: bool,
+    #[arg(short, long, help = "Output file (only valid with a single directory)")]
+    pub output: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Directory to place output files in instead of next to the inputs"
+    )]
+    pub out_dir: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Cbz, help = "Archive format")]
+    pub format: ArchiveFormat,
+    #[arg(
+        long,
+        value_name = "EXT",
+        value_parser = parse_extension,
+        help = "Extension of output files instead of the one of the format"
+    )]
+    pub extension: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Compression method (cbz only) [default: stored]"
+    )]
+    pub compression: Option<Compression>,
+    #[arg(
+        long,
+        help = "Compress every page instead of storing already compressed JPEG, GIF, WebP and AVIF pages"
+    )]
+    pub no_smart_compression: bool,
+    #[arg(
+        long,
+        help = "Compression level (deflate: 1-9, bzip2: 1-9, zstd: 1-22, default 10)"
+    )]
+    pub level: Option<i64>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = Config::load,
+        help = "TOML file setting the compression method and level per kind of entry (cbz only)"
+    )]
+    pub config: Option<Config>,
+    #[arg(long, value_enum, help = "Convert images to format")]
+    pub convert: Option<ConvertFormat>,
+    #[arg(long, help = "Convert CMYK JPEG images to RGB")]
+    pub fix_cmyk: bool,
+    #[arg(
+        long,
+        help = "Convert 16-bit and paletted PNG images to 8-bit RGB or RGBA"
+    )]
+    pub normalize_png: bool,
+    #[arg(
+        long,
+        default_value_t = 80,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        help = "Quality for lossy encoding (0-100)"
+    )]
+    pub quality: u8,
+    #[arg(
+        long,
+        conflicts_with = "quality",
+        help = "Encode WebP images losslessly, e.g. for line art"
+    )]
+    pub webp_lossless: bool,
+    #[arg(
+        long,
+        value_enum,
+        help = "Chroma subsampling of re-encoded JPEG images [default: 444]"
+    )]
+    pub jpeg_subsampling: Option<JpegSubsampling>,
+    #[arg(
+        long,
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u8).range(1..=10),
+        help = "Speed of AVIF encoding, trading compression for speed (1-10)"
+    )]
+    pub avif_speed: u8,
+    #[arg(long, help = "Downscale images wider than this")]
+    pub max_width: Option<u32>,
+    #[arg(long, help = "Downscale images taller than this")]
+    pub max_height: Option<u32>,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "lanczos3",
+        help = "Filter used for resizing"
+    )]
+    pub resize_filter: ResizeFilter,
+    #[arg(
+        long,
+        help = "Rotate images upright according to their EXIF orientation"
+    )]
+    pub auto_orient: bool,
+    #[arg(long, help = "Strip metadata from images")]
+    pub strip_metadata: bool,
+    #[arg(long, help = "Split landscape images into two pages")]
+    pub split_spreads: bool,
+    #[arg(
+        long,
+        requires = "split_spreads",
+        help = "Never split the first image, e.g. a cover spanning a spread"
+    )]
+    pub no_split_first: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "ltr",
+        help = "Reading direction for split spreads"
+    )]
+    pub split_direction: SplitDirection,
+    #[arg(long, help = "Convert images with color to grayscale")]
+    pub grayscale: bool,
+    #[arg(long, help = "Convert all images to grayscale")]
+    pub force_grayscale: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u16).range(2..=256),
+        help = "Number of gray levels to reduce grayscale images to, e.g. 16 for e-ink (2-256) [default: 256]"
+    )]
+    pub gray_levels: Option<u16>,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "none",
+        help = "Dithering used when converting to grayscale"
+    )]
+    pub dither: Dither,
+    #[arg(long, help = "Trim uniform borders from images")]
+    pub trim: bool,
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "Maximum difference from the border color when trimming (0-255)"
+    )]
+    pub trim_tolerance: u8,
+    #[arg(long, value_name = "FILENAME", help = "Image to use as the first page")]
+    pub cover: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["cover", "append"],
+        help = "Image from outside the directory to add as the first page"
+    )]
+    pub cover_file: Option<PathBuf>,
+    #[arg(long, help = "Title for generated ComicInfo.xml")]
+    pub title: Option<String>,
+    #[arg(long, help = "Series for generated ComicInfo.xml")]
+    pub series: Option<String>,
+    #[arg(long, help = "Issue number for generated ComicInfo.xml")]
+    pub number: Option<String>,
+    #[arg(long, help = "Volume number for generated ComicInfo.xml")]
+    pub volume: Option<u32>,
+    #[arg(long, help = "Writer for generated ComicInfo.xml")]
+    pub writer: Option<String>,
+    #[arg(long, help = "Publication year for generated ComicInfo.xml")]
+    pub year: Option<u16>,
+    #[arg(long, help = "Fill in ComicInfo.xml fields from the directory name")]
+    pub parse_folder_name: bool,
+    #[arg(
+        long,
+        help = "Bookmark the first page of each subdirectory as a chapter in ComicInfo.xml"
+    )]
+    pub include_subdir_as_chapter: bool,
+    #[arg(long, help = "Add a manifest with the SHA-256 of every entry")]
+    pub manifest: bool,
+    #[arg(
+        long,
+        conflicts_with = "append",
+        help = "Add a toc.txt listing the page range of each subdirectory as a chapter"
+    )]
+    pub toc: bool,
+    #[arg(long, help = "Show what would be done without writing anything")]
+    pub dry_run: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["no_rename", "delete", "trash", "cover_file", "sample"],
+        help = "Rename the images in place instead of creating an archive"
+    )]
+    pub rename_only: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["delete", "trash", "rename_only"],
+        help = "List the format, dimensions, color type, size and decodability of every file instead of creating an archive"
+    )]
+    pub image_info: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with = "append",
+        help = "Only pack the first N images into a sample archive, never deleting anything"
+    )]
+    pub sample: Option<NonZeroUsize>,
+}
+
+impl Default for CbzOptions {
+    fn default() -> Self {
+        /// Command line with nothing but the options, to get their defaults.
+        #[derive(Parser)]
+        struct Defaults {
+            #[command(flatten)]
+            options: CbzOptions,
+        }
+
+        Defaults::parse_from(["makecbz"]).options
+    }
+}
+
+impl CbzOptions {
+    /// Returns the compression method of entries without a rule in `config`.
+    ///
+    /// `compression` overrides the method at the top of the config, and entries are stored if neither gives one.
+    pub fn compression_method(&self) -> Compression {
+        self.compression
+            .or(self.config.as_ref().and_then(|config| config.method))
+            .unwrap_or(Compression::Stored)
+    }
+
+    /// Checks that the options can be used together and are supported by this build.
+    ///
+    /// Fails describing the first problem found. Otherwise returns warnings about options which have no or little
+    /// effect in combination with the others.
+    pub fn validate(&self) -> Result<Vec<String>> {
+        if self.trash && !cfg!(feature = "trash") {
+            anyhow::bail!("--trash is not supported by this build");
+        }
+        let format = self
+            .format
+            .to_possible_value()
+            .expect("No format is skipped");
+        if !matches!(self.format, ArchiveFormat::Cbz)
+            && (self.compression.is_some() || self.level.is_some() || self.config.is_some())
+        {
+            anyhow::bail!(
+                "--compression, --level and --config can not be used with --format {}",
+                format.get_name()
+            );
+        }
+        // Checking the level here makes it fail once, rather than for every directory.
+        EntryOptions::new(self)?;
+        if self.cover_file.is_some() && self.start_number == 0 {
+            anyhow::bail!(
+                "--cover-file can not be used with --start-number 0, as the cover is numbered before the first page"
+            );
+        }
+        if self.toc && matches!(self.format, ArchiveFormat::Pdf) {
+            anyhow::bail!("--toc can not be used with --format pdf, as a pdf can only hold images");
+        }
+        if self.max_archive_size.is_some() && !matches!(self.format, ArchiveFormat::Cbz) {
+            anyhow::bail!("--max-archive-size can only be used with --format cbz");
+        }
+        if self.append && !matches!(self.format, ArchiveFormat::Cbz) {
+            anyhow::bail!("--append can only be used with --format cbz");
+        }
+        if matches!(self.format, ArchiveFormat::Pdf) && self.manifest {
+            anyhow::bail!("--manifest can not be used with --format pdf");
+        }
+        if matches!(self.format, ArchiveFormat::Pdf) && self.verify_output {
+            anyhow::bail!("--verify-output can not be used with --format pdf");
+        }
+        if matches!(self.format, ArchiveFormat::Pdf)
+            && matches!(self.convert, Some(ConvertFormat::Avif))
+        {
+            anyhow::bail!("--convert avif can not be used with --format pdf");
+        }
+        if let Some(convert) = self.convert
+            && !convert.is_supported()
+        {
+            anyhow::bail!(
+                "Converting to {} is not supported by this build",
+                convert
+                    .to_possible_value()
+                    .expect("No format is skipped")
+                    .get_name()
+            );
+        }
+
+        let mut warnings = Vec::new();
+        let compression = self.compression_method();
+        if self.level.is_some() && compression.levels().is_none() {
+            warnings.push(format!(
+                "--level has no effect with --compression {compression}"
+            ));
+        }
+        if (self.gray_levels.is_some() || self.dither != Dither::None)
+            && !self.grayscale
+            && !self.force_grayscale
+        {
+            warnings.push(
+                "--gray-levels and --dither have no effect without --grayscale or --force-grayscale"
+                    .to_string(),
+            );
+        }
+        if self.webp_lossless && self.convert != Some(ConvertFormat::Webp) {
+            warnings.push("--webp-lossless has little effect without --convert webp, as only WebP images are re-encoded losslessly".to_string());
+        }
+        // JPEG images are only encoded when converting to JPEG or when transforming JPEG images which aren't converted.
+        if self.jpeg_subsampling.is_some() {
+            match self.convert {
+                Some(ConvertFormat::Jpeg) => {}
+                Some(convert) => warnings.push(format!(
+                    "--jpeg-subsampling has no effect with --convert {}, as no JPEG images are encoded",
+                    convert
+                        .to_possible_value()
+                        .expect("No format is skipped")
+                        .get_name()
+                )),
+                None if !transforms_images(self) && !converts_cmyk(self) => warnings.push(
+                    "--jpeg-subsampling has no effect without --convert jpeg or a transformation, as JPEG images are copied as they are"
+                        .to_string(),
+                ),
+                None => {}
+            }
+        }
+        Ok(warnings)
+    }
+}
+
+/// Parses an output file extension, with or without a leading dot.
+///
+/// Extensions may only consist of ASCII letters, digits, dots, dashes and underscores, so that they can't contain path
+/// separators.
+fn parse_extension(extension: &str) -> Result<String> {
+    let extension = extension.strip_prefix('.').unwrap_or(extension);
+    if extension.is_empty()
+        || !extension
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    {
+        anyhow::bail!("Extension must only contain letters, digits, '.', '-' and '_'");
+    }
+    Ok(extension.to_string())
+}
+
+/// Parses a size in bytes, optionally followed by a unit.
+///
+/// Units are `K`, `M` and `G` or `KB`, `MB` and `GB` for powers of 1000, and `KiB`, `MiB` and `GiB` for powers of 1024,
+/// ignoring case. Sizes must be positive whole numbers.
+fn parse_size(size: &str) -> Result<u64> {
+    let (number, unit) = size.split_at(
+        size.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(size.len()),
+    );
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => anyhow::bail!("Unknown unit {unit}, expected K, M, G, KiB, MiB or GiB"),
+    };
+    let size = number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .context("Size must be a whole number of bytes, optionally followed by a unit")?;
+    if size == 0 {
+        anyhow::bail!("Size must be greater than zero");
+    }
+    Ok(size)
+}
+
+/// Image information.
+///
+/// Stores the path and guessed format of an image, whether it is animated, the number of frames to add as separate
+/// pages, whether it is a CMYK JPEG or a PNG to be normalized, its dimensions, as well as the SHA-256 digest of its
+/// contents if deduplicating, its perceptual hash if deduplicating adjacent images and its size, modification time or
+/// EXIF capture time if sorting by it.
+pub struct ImageInfo {
+    pub path: PathBuf,
+    pub format: ImageFormat,
+    pub animated: bool,
+    pub frames: usize,
+    pub cmyk: bool,
+    pub normalize: bool,
+    pub dimensions: (u32, u32),
+    pub digest: Option<[u8; 32]>,
+    pub phash: Option<u64>,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub capture_time: Option<String>,
+}
+
+/// Path of a non-image, along with the reason it is not considered an image.
+pub type NonImage = (PathBuf, &'static str);
+
+/// Reason given for files which could not be read.
+const UNREADABLE: &str = "unreadable file";
+
+/// Classification of a path found while checking a directory.
+pub enum Entry {
+    Image(ImageInfo),
+    NonImage(PathBuf, &'static str),
+    /// File which could not be opened or read, along with the error.
+    Unreadable(PathBuf, anyhow::Error),
+    Excluded(PathBuf),
+    Skipped(PathBuf),
+    Ignored(PathBuf),
+}
+
+/// Checks if a path is a hidden or system file or directory.
+///
+/// Hidden files are those whose name starts with a dot, e.g. `.DS_Store`.
+fn is_hidden(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.starts_with('.') || SYSTEM_FILES.contains(&name.as_ref())
+}
+
+/// Checks if a path is named ComicInfo.xml, ignoring case if `normalize_comic_info` is true.
+fn is_comic_info(path: &Path, options: &CbzOptions) -> bool {
+    let name = path.file_name().unwrap_or_default();
+    name == comic_info::FILE_NAME
+        || (options.normalize_comic_info && name.eq_ignore_ascii_case(comic_info::FILE_NAME))
+}
+
+/// Checks if a path is an excluded file.
+///
+/// Excluded files are `EXCLUDED_FILES` unless `no_exclude` is true, along with any files in `include_as_is`. Names are
+/// matched exactly, ignoring case on Windows. ComicInfo.xml is matched ignoring case if `normalize_comic_info` is true.
+fn is_excluded(path: &Path, options: &CbzOptions) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let matches = |excluded: &str| {
+        if cfg!(windows) {
+            excluded.to_lowercase() == name.to_lowercase()
+        } else {
+            excluded == name
+        }
+    };
+
+    (!options.no_exclude
+        && (EXCLUDED_FILES.into_iter().any(matches) || is_comic_info(path, options)))
+        || options
+            .include_as_is
+            .iter()
+            .any(|excluded| matches(excluded))
+}
+
+/// Compares two strings in natural order.
+///
+/// Runs of digits are compared by their numeric value, everything else is compared character by character. Numbers
+/// which are equal in value but differ in leading zeros (e.g. `7` and `007`) are ordered by length, and strings which
+/// are otherwise equal fall back to a plain lexical comparison so that the ordering is total.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    loop {
+        match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let mut x_digits = String::new();
+                while let Some(c) = a_chars.next_if(char::is_ascii_digit) {
+                    x_digits.push(c);
+                }
+                let mut y_digits = String::new();
+                while let Some(c) = b_chars.next_if(char::is_ascii_digit) {
+                    y_digits.push(c);
+                }
+
+                let x_trimmed = x_digits.trim_start_matches('0');
+                let y_trimmed = y_digits.trim_start_matches('0');
+                let ordering = x_trimmed
+                    .len()
+                    .cmp(&y_trimmed.len())
+                    .then_with(|| x_trimmed.cmp(y_trimmed))
+                    .then_with(|| x_digits.len().cmp(&y_digits.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.cmp(y);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+/// Loads the ignore patterns for a directory.
+///
+/// Patterns are read from `ignore_file` if provided, else from `IGNORE_FILE` inside `dir` if it exists, and are matched
+/// relative to `dir`. Errors are propagated with added context.
+fn load_ignore(dir: &Path, options: &CbzOptions) -> Result<Gitignore> {
+    let path = match &options.ignore_file {
+        Some(path) => path.clone(),
+        None => dir.join(IGNORE_FILE),
+    };
+    if options.ignore_file.is_none() && !path.is_file() {
+        return Ok(Gitignore::empty());
+    }
+
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(&path) {
+        return Err(e).with_context(|| format!("Failed to read ignore file {}", path.display()));
+    }
+    builder
+        .build()
+        .with_context(|| format!("Failed to read ignore file {}", path.display()))
+}
+
+/// Checks if a path inside `input` is matched by the ignore patterns.
+///
+/// The ignore file itself is always ignored, as is any manifest or table of contents left over from repacking an
+/// archive.
+fn is_ignored(input: &Input, ignore: &Gitignore, path: &Path) -> bool {
+    path.file_name() == Some(IGNORE_FILE.as_ref())
+        || path.file_name() == Some(MANIFEST_FILE.as_ref())
+        || path.file_name() == Some(TOC_FILE.as_ref())
+        || ignore
+            .matched_path_or_any_parents(path, input.is_dir(path))
+            .is_ignore()
+}
+
+/// Checks if a symlinked directory points to itself or to any directory containing it.
+///
+/// Descending into such a directory would never end. Paths which can't be resolved are not considered cycles.
+fn is_symlink_cycle(path: &Path) -> bool {
+    let Ok(target) = fs::canonicalize(path) else {
+        return false;
+    };
+    path.ancestors()
+        .skip(1)
+        .any(|dir| fs::canonicalize(dir).is_ok_and(|dir| dir == target))
+}
+
+/// Returns a sorted list of all paths in the provided directory of `input`.
+///
+/// Paths are sorted in natural order unless `lexical_sort` is true. If `recursive` is true subdirectories are replaced
+/// in place by their own sorted contents, so that the returned list only contains files. Hidden subdirectories are not
+/// descended into if `skip_hidden` is true, and neither are subdirectories matched by `ignore`. Symlinked
+/// subdirectories are only descended into if `follow_symlinks` is true and they don't form a cycle as checked by
+/// `is_symlink_cycle`. Subdirectories which are not descended into are returned as they are. Propagates any error
+/// with added context.
+fn get_paths<P>(
+    input: &Input,
+    dir: P,
+    lexical_sort: bool,
+    recursive: bool,
+    skip_hidden: bool,
+    follow_symlinks: bool,
+    ignore: &Gitignore,
+) -> Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    let mut paths = input.read_dir(dir.as_ref())?;
+
+    if lexical_sort {
+        paths.sort();
+    } else {
+        paths.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    }
+
+    if recursive {
+        let mut all_paths = Vec::new();
+        for path in paths {
+            if input.is_dir(&path)
+                && !(skip_hidden && is_hidden(&path))
+                && !is_ignored(input, ignore, &path)
+                && !(input.is_symlink(&path) && (!follow_symlinks || is_symlink_cycle(&path)))
+            {
+                all_paths.extend(get_paths(
+                    input,
+                    &path,
+                    lexical_sort,
+                    recursive,
+                    skip_hidden,
+                    follow_symlinks,
+                    ignore,
+                )?);
+            } else {
+                all_paths.push(path);
+            }
+        }
+        paths = all_paths;
+    }
+
+    Ok(paths)
+}
+
+/// Checks if a TIFF file contains more than one page.
+///
+/// Only the headers are read. Errors are propagated with added context.
+fn is_multi_page(input: &Input, file: &Path) -> Result<bool> {
+    let decoder = tiff::decoder::Decoder::new(input.reader(file)?)
+        .with_context(|| format!("Failed to read TIFF header of {}", file.display()))?;
+
+    Ok(decoder.more_images())
+}
+
+/// Counts the frames of a GIF image, stopping at `limit`.
+///
+/// Only the frame headers are read, skipping over the compressed pixel data without decoding it.
+fn gif_frames<R: io::Read>(reader: R, limit: usize) -> Result<usize, gif::DecodingError> {
+    let mut options = gif::DecodeOptions::new();
+    options.skip_frame_decoding(true);
+    let mut decoder = options.read_info(reader)?;
+    let mut count = 0;
+    while count < limit && decoder.next_frame_info()?.is_some() {
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Checks if a GIF or WebP file contains more than one frame.
+///
+/// Only the headers of at most the first two frames are read, as described in `gif_frames`. Errors are propagated with
+/// added context.
+fn is_animated(input: &Input, file: &Path, format: ImageFormat) -> Result<bool> {
+    let reader = input.reader(file)?;
+    let context = || format!("Failed to read frames of {}", file.display());
+    Ok(match format {
+        ImageFormat::Gif => gif_frames(reader, 2).with_context(context)? > 1,
+        ImageFormat::WebP => WebPDecoder::new(reader)
+            .with_context(context)?
+            .has_animation(),
+        _ => false,
+    })
+}
+
+/// Counts the frames of a GIF or WebP file.
+///
+/// Only the headers are read, without decoding any frame. Errors are propagated with added context.
+fn frame_count(input: &Input, file: &Path, format: ImageFormat) -> Result<usize> {
+    let reader = input.reader(file)?;
+    let context = || format!("Failed to read frames of {}", file.display());
+    Ok(match format {
+        ImageFormat::Gif => gif_frames(reader, usize::MAX).with_context(context)?,
+        ImageFormat::WebP => image_webp::WebPDecoder::new(reader)
+            .with_context(context)?
+            .num_frames() as usize,
+        _ => 1,
+    })
+}
+
+/// Decodes every frame of an animated GIF or WebP image.
+///
+/// Frames are returned as RGBA images of the full size of the animation. Errors are propagated with added context.
+fn decode_frames(img: &ImageInfo, buf: &[u8]) -> Result<Vec<DynamicImage>> {
+    let context = || format!("Failed to decode frames of {}", img.path.display());
+    let frames = match img.format {
+        ImageFormat::Gif => GifDecoder::new(io::Cursor::new(buf))
+            .with_context(context)?
+            .into_frames(),
+        ImageFormat::WebP => WebPDecoder::new(io::Cursor::new(buf))
+            .with_context(context)?
+            .into_frames(),
+        format => anyhow::bail!("{format:?} images have no frames"),
+    };
+    frames
+        .map(|frame| frame.map(|frame| DynamicImage::ImageRgba8(frame.into_buffer())))
+        .collect::<Result<_, _>>()
+        .with_context(context)
+}
+
+/// Checks if a PNG file has a bit depth other than 8 or a palette.
+///
+/// Only the header is read. Files which are malformed or end early are not considered to need normalizing. Errors are
+/// propagated.
+fn needs_normalizing(input: &Input, file: &Path) -> Result<bool> {
+    // The signature is followed by the IHDR chunk, whose data starts with the width and height, then the bit depth and
+    // color type.
+    let mut header = [0; 26];
+    if input.reader(file)?.read_exact(&mut header).is_err() || &header[12..16] != b"IHDR" {
+        return Ok(false);
+    }
+    let (bit_depth, color_type) = (header[24], header[25]);
+
+    Ok(bit_depth != 8 || color_type == 3)
+}
+
+/// Checks if a JPEG file is in the CMYK or YCCK color space, i.e. has four color components.
+///
+/// Only the segments up to the start of the frame are read, skipping over the contents of all others, so that the image
+/// is not decoded. Files which are malformed or end early are not considered CMYK. Errors are propagated with added
+/// context.
+fn is_cmyk(input: &Input, file: &Path) -> Result<bool> {
+    let mut reader = input.reader(file)?;
+    let mut soi = [0; 2];
+    if reader.read_exact(&mut soi).is_err() || soi != [0xFF, 0xD8] {
+        return Ok(false);
+    }
+    loop {
+        let mut header = [0; 4];
+        if reader.read_exact(&mut header).is_err() || header[0] != 0xFF {
+            return Ok(false);
+        }
+        match header[1] {
+            // Start of frame markers, except for DHT, JPG and DAC which share their range.
+            0xC0..=0xCF if !matches!(header[1], 0xC4 | 0xC8 | 0xCC) => {
+                // Precision, height and width come before the number of components.
+                let mut frame = [0; 6];
+                return Ok(reader.read_exact(&mut frame).is_ok() && frame[5] == 4);
+            }
+            // Start of scan, which must not come before the frame.
+            0xDA => return Ok(false),
+            _ => {
+                let length = u16::from_be_bytes([header[2], header[3]]);
+                reader
+                    .seek(io::SeekFrom::Current(i64::from(length) - 2))
+                    .with_context(|| format!("Failed to read JPEG header of {}", file.display()))?;
+            }
+        }
+    }
+}
+
+/// Checks if a file is a valid image.
+///
+/// If it is a supported image, returns `Entry::Image` with the path and guessed format, else returns `Entry::NonImage`.
+/// Empty files are rejected right away, and files which can't be opened or read are returned as `Entry::Unreadable` so
+/// that a single inaccessible file doesn't abort the whole scan. If `verify` is true the image is decoded to ensure
+/// there is no corruption, else only its header is read so that truncated downloads are still caught cheaply. Either
+/// way its dimensions are recorded, whether it is a CMYK JPEG as checked by `is_cmyk`, and if `normalize_png` is true
+/// whether it is a PNG which needs normalizing as checked by `needs_normalizing`. Multi-page TIFFs are rejected with an
+/// error, and animated images are dealt with according to `animated`. TIFF, GIF, WebP and JPEG images whose headers
+/// can't be read while checking for multiple pages, frames or CMYK are returned as non-images. Propagates any error
+/// with added context.
+pub fn check_file<P>(input: &Input, file: P, options: &CbzOptions) -> Result<Entry>
+where
+    P: AsRef<Path>,
+{
+    let file = file.as_ref();
+    let mut reader = match input.reader(file) {
+        Ok(reader) => reader,
+        Err(err) => return Ok(Entry::Unreadable(file.to_path_buf(), err)),
+    };
+    let non_image = |reason| Ok(Entry::NonImage(file.to_path_buf(), reason));
+    match reader.fill_buf() {
+        Ok([]) => return non_image("empty file"),
+        Ok(_) => {}
+        Err(err) => {
+            return Ok(Entry::Unreadable(
+                file.to_path_buf(),
+                anyhow::Error::new(err).context(format!("Failed to read file {}", file.display())),
+            ));
+        }
+    }
+    let image = ImageReader::new(reader)
+        .with_guessed_format()
+        .with_context(|| format!("Failed to read file {}", file.display()))?;
+
+    if let Some(format) = image.format()
+        && FORMATS.contains(&format)
+    {
+        let multi_page = match format {
+            ImageFormat::Tiff => match is_multi_page(input, file) {
+                Ok(multi_page) => multi_page,
+                Err(_) => return non_image("truncated or corrupt header"),
+            },
+            _ => false,
+        };
+        if multi_page {
+            anyhow::bail!(
+                "{} is a multi-page TIFF, which is not supported",
+                file.display()
+            );
+        }
+        let Ok(animated) = is_animated(input, file, format) else {
+            return non_image("corrupt header");
+        };
+        let frames = if animated && options.explode_frames {
+            match frame_count(input, file, format) {
+                Ok(frames) => frames,
+                Err(_) => return non_image("corrupt header"),
+            }
+        } else {
+            1
+        };
+        if animated && !options.explode_frames {
+            match options.animated {
+                AnimatedPolicy::Reject => return non_image("animated image"),
+                AnimatedPolicy::FirstFrame => {}
+                AnimatedPolicy::Error => {
+                    anyhow::bail!("{} is animated, which is not supported", file.display())
+                }
+            }
+        }
+        let cmyk = match format {
+            ImageFormat::Jpeg => match is_cmyk(input, file) {
+                Ok(cmyk) => cmyk,
+                Err(_) => return non_image("truncated or corrupt header"),
+            },
+            _ => false,
+        };
+        let normalize =
+            options.normalize_png && format == ImageFormat::Png && needs_normalizing(input, file)?;
+        let dimensions = if options.verify {
+            match image.decode() {
+                Ok(image) => (image.width(), image.height()),
+                Err(_) => return non_image("unsupported or corrupt image"),
+            }
+        } else {
+            match image.into_dimensions() {
+                Ok(dimensions) => dimensions,
+                Err(_) => return non_image("truncated or corrupt header"),
+            }
+        };
+
+        return Ok(Entry::Image(ImageInfo {
+            path: file.to_path_buf(),
+            format,
+            animated,
+            frames,
+            cmyk,
+            normalize,
+            dimensions,
+            digest: None,
+            phash: None,
+            size: 0,
+            modified: None,
+            capture_time: None,
+        }));
+    }
+
+    non_image("unsupported image")
+}
+
+/// Compares two optional sort keys, ordering missing keys after all present ones.
+fn missing_last<T: Ord>(a: Option<&T>, b: Option<&T>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Reads the EXIF capture time of an image.
+///
+/// Returns the DateTimeOriginal tag formatted as `YYYY-MM-DD HH:MM:SS`, which sorts chronologically, or None if the
+/// image has no such tag or its EXIF data is malformed. Errors reading the image are propagated with added context.
+fn capture_time(input: &Input, img: &ImageInfo) -> Result<Option<String>> {
+    let mut decoder = ImageReader::with_format(input.reader(&img.path)?, img.format)
+        .into_decoder()
+        .with_context(|| format!("Failed to read header of {}", img.path.display()))?;
+    let Some(raw) = decoder
+        .exif_metadata()
+        .with_context(|| format!("Failed to read metadata of {}", img.path.display()))?
+    else {
+        return Ok(None);
+    };
+
+    let Ok(exif) = exif::Reader::new().read_raw(raw) else {
+        return Ok(None);
+    };
+    let capture_time = exif
+        .get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
+        .and_then(|field| match &field.value {
+            exif::Value::Ascii(values) => values.first(),
+            _ => None,
+        })
+        .and_then(|value| exif::DateTime::from_ascii(value).ok())
+        .map(|date_time| date_time.to_string());
+
+    Ok(capture_time)
+}
+
+/// Computes the SHA-256 digest of a file.
+///
+/// Errors are propagated with added context.
+fn digest(input: &Input, file: &Path) -> Result<[u8; 32]> {
+    let mut reader = input.reader(file)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)
+        .with_context(|| format!("Failed to read file {}", file.display()))?;
+
+    Ok(hasher.finalize().into())
+}
+
+/// Computes the perceptual hash of an image.
+///
+/// The hash is a difference hash: the image is shrunk to 9x8 grayscale pixels, and every bit tells whether a pixel is
+/// darker than its right neighbour. Images looking alike have hashes differing in only a few bits, even if they were
+/// re-encoded or slightly resized. Errors are propagated with added context.
+fn phash(input: &Input, img: &ImageInfo) -> Result<u64> {
+    let image = ImageReader::with_format(input.reader(&img.path)?, img.format)
+        .decode()
+        .with_context(|| format!("Failed to decode {}", img.path.display()))?;
+    let small = image::imageops::resize(&image.to_luma8(), 9, 8, FilterType::Triangle);
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            hash |= u64::from(small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0]);
+        }
+    }
+
+    Ok(hash)
+}
+
+/// Reports an anomaly, which is warned about unless `strict` is true in which case `msg` is returned as an error.
+fn anomaly(msg: String, options: &CbzOptions, out: &mut Output) -> Result<()> {
+    if options.strict {
+        anyhow::bail!(msg);
+    }
+    out.warn(msg);
+    Ok(())
+}
+
+/// Collapses runs of visually near-identical adjacent images into their first image.
+///
+/// Images are compared with the first image of the run by the Hamming distance of their perceptual hashes as computed
+/// by `phash`, and are dropped if at most `phash_threshold` bits differ. Every dropped image is reported along with the
+/// image it resembles, as similar pages are not necessarily duplicates.
+fn dedupe_adjacent(imgs: Vec<ImageInfo>, options: &CbzOptions, out: &mut Output) -> Vec<ImageInfo> {
+    let mut kept: Vec<ImageInfo> = Vec::with_capacity(imgs.len());
+    let mut dropped = Vec::new();
+    for img in imgs {
+        if let Some(first) = kept.last()
+            && let (Some(a), Some(b)) = (first.phash, img.phash)
+            && (a ^ b).count_ones() <= options.phash_threshold
+        {
+            dropped.push(format!(
+                "{}: similar to {} ({} of 64 bits differ)",
+                img.path.display(),
+                first.path.display(),
+                (a ^ b).count_ones()
+            ));
+            continue;
+        }
+        kept.push(img);
+    }
+    if !dropped.is_empty() {
+        out.info(format!(
+            "Collapsed {} near-identical adjacent images",
+            dropped.len()
+        ));
+        for image in dropped {
+            out.info(format!("\t{image}"));
+        }
+    }
+    kept
+}
+
+/// Checks the verified images for unusual aspect ratios.
+///
+/// Images whose aspect ratio deviates by more than `MAX_ASPECT_DEVIATION` from the median are reported along with their
+/// dimensions, which usually points to an ad banner or a page at the wrong resolution. Spreads are compared by the
+/// aspect ratio of their halves if they are split as described in `is_spread`. Unusual images are warned about, unless
+/// `strict` is true in which case an error is returned.
+fn check_dimensions(imgs: &[ImageInfo], options: &CbzOptions, out: &mut Output) -> Result<()> {
+    let aspect_ratio = |idx: usize, (width, height): (u32, u32)| {
+        let width = if is_spread(width, height, idx == 0, options) {
+            width.div_ceil(2)
+        } else {
+            width
+        };
+        f64::from(width) / f64::from(height.max(1))
+    };
+    let mut ratios = imgs
+        .iter()
+        .enumerate()
+        .map(|(idx, img)| aspect_ratio(idx, img.dimensions))
+        .collect::<Vec<_>>();
+    if ratios.is_empty() {
+        return Ok(());
+    }
+    ratios.sort_by(f64::total_cmp);
+    let median = ratios[ratios.len() / 2];
+
+    let unusual = imgs
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, img)| {
+            let dimensions = img.dimensions;
+            let deviation = (aspect_ratio(idx, dimensions) - median).abs() / median;
+            (deviation > MAX_ASPECT_DEVIATION)
+                .then(|| format!("{} ({}x{})", img.path.display(), dimensions.0, dimensions.1))
+        })
+        .collect::<Vec<_>>();
+    if unusual.is_empty() {
+        return Ok(());
+    }
+    if options.strict {
+        anyhow::bail!(
+            "{} images have unusual aspect ratios: {}",
+            unusual.len(),
+            unusual.join(", ")
+        );
+    }
+    out.warn(format!(
+        "{} images have unusual aspect ratios (median {median:.2})",
+        unusual.len()
+    ));
+    for image in unusual {
+        out.info(format!("\t{image}"));
+    }
+
+    Ok(())
+}
+
+/// Files found by `check_dir`.
+pub struct Scan {
+    /// Supported images, in page order.
+    pub images: Vec<ImageInfo>,
+    /// Non-images and unsupported images, along with the reason they were rejected.
+    pub non_images: Vec<NonImage>,
+    /// Excluded files, such as any existing ComicInfo.xml.
+    pub excluded: Vec<PathBuf>,
+}
+
+/// Checks the directory or archive of `input` for images.
+///
+/// Returns the supported image files, non-image files or non-supported files along with the reason they were rejected,
+/// and excluded files, in page order. The options only decide which files are scanned, how they are ordered and which
+/// are dropped, as described for each of them. Files which can't be read are returned as non-images, whereas a
+/// directory which can't be read is an error. Anomalies are reported as described in `anomaly`. Propagates any error.
+pub fn check_dir(input: &Input, options: &CbzOptions, out: &mut Output) -> Result<Scan> {
+    let dir = input.root();
+    if let Some(merged) = input.merged() {
+        out.info(format!("Checking {} archives ...", merged.len()));
+    } else if input.is_archived() {
+        out.info("Checking archive ...");
+    } else if input.listed().is_some() {
+        out.info("Checking listed paths ...");
+    } else {
+        out.info("Checking directory ...");
+    }
+    let skip_hidden = !options.no_skip_hidden;
+    let lexical_sort = options.lexical_sort || options.sort_by == SortBy::Lexical;
+    let recursive = options.recursive || input.is_archived();
+    // Listed paths are taken in the order given, with only listed directories being scanned.
+    let (ignore, paths) = if let Some(entries) = input.listed() {
+        let ignore = Gitignore::empty();
+        let mut paths = Vec::new();
+        for entry in entries {
+            if input.is_dir(entry) {
+                paths.extend(get_paths(
+                    input,
+                    entry,
+                    lexical_sort,
+                    recursive,
+                    skip_hidden,
+                    options.follow_symlinks,
+                    &ignore,
+                )?);
+            } else {
+                paths.push(entry.clone());
+            }
+        }
+        (ignore, paths)
+    } else {
+        let ignore = load_ignore(dir, options)?;
+        let paths = get_paths(
+            input,
+            dir,
+            lexical_sort,
+            recursive,
+            skip_hidden,
+            options.follow_symlinks,
+            &ignore,
+        )?;
+        (ignore, paths)
+    };
+    let bar = ProgressBar::new(
+        paths
+            .len()
+            .try_into()
+            .context("Failed to set progress bar length")?,
+    );
+    bar.set_style(
+        ProgressStyle::with_template(if options.verify {
+            "Verifying files {bar:40} {pos}/{len}"
+        } else {
+            "Checking files {bar:40} {pos}/{len}"
+        })
+        .context("Failed to set progress bar style")?,
+    );
+    // Checking files without decoding them is quick, so the bar is only worth showing for many files.
+    let show_bar =
+        options.verify || options.dedupe_adjacent || paths.len() > CHECK_PROGRESS_THRESHOLD;
+    let bar = if show_bar {
+        out.add_progress(bar)
+    } else {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+        bar
+    };
+    let entries = paths
+        .into_par_iter()
+        .map(|path| {
+            let entry = if is_ignored(input, &ignore, &path) {
+                Ok(Entry::Ignored(path))
+            } else if skip_hidden && is_hidden(&path) {
+                Ok(Entry::Skipped(path))
+            } else if recursive && input.is_dir(&path) && input.is_symlink(&path) {
+                let reason = if options.follow_symlinks {
+                    "symlink cycle"
+                } else {
+                    "symlinked directory"
+                };
+                Ok(Entry::NonImage(path, reason))
+            } else if !input.is_file(&path) {
+                Ok(Entry::NonImage(path, "not a file"))
+            } else if input.is_top_level(&path) && is_excluded(&path, options) {
+                Ok(Entry::Excluded(path))
+            } else {
+                check_file(input, &path, options).and_then(|entry| match entry {
+                    Entry::Image(mut image_info) => {
+                        if options.dedupe {
+                            image_info.digest = Some(digest(input, &path)?);
+                        }
+                        if options.dedupe_adjacent {
+                            image_info.phash = Some(phash(input, &image_info)?);
+                        }
+                        match options.sort_by {
+                            SortBy::Lexical | SortBy::Natural => {}
+                            SortBy::Size => image_info.size = input.size(&path)?,
+                            SortBy::Mtime => image_info.modified = input.modified(&path).ok(),
+                            SortBy::ExifDate => {
+                                image_info.capture_time = capture_time(input, &image_info)?;
+                            }
+                        }
+                        Ok(Entry::Image(image_info))
+                    }
+                    entry => Ok(entry),
+                })
+            };
+
+            bar.inc(1);
+            entry
+        })
+        .collect::<Vec<_>>();
+    if show_bar {
+        out.finish_progress(&bar);
+    }
+
+    let mut imgs = Vec::new();
+    let mut non_imgs = Vec::new();
+    let mut excluded = Vec::new();
+    let mut skipped = 0;
+    let mut ignored = 0;
+    let mut originals: HashMap<_, PathBuf> = HashMap::new();
+    let mut duplicates = 0;
+    let mut small = Vec::new();
+    for entry in entries {
+        match entry? {
+            Entry::Image(image_info) => {
+                let (width, height) = image_info.dimensions;
+                if options
+                    .min_dimension
+                    .is_some_and(|min_dimension| width.min(height) < min_dimension)
+                {
+                    out.verbose(format!(
+                        "\t{}: too small ({width}x{height})",
+                        image_info.path.display()
+                    ));
+                    small.push(format!("{} ({width}x{height})", image_info.path.display()));
+                    continue;
+                }
+                if let Some(digest) = image_info.digest {
+                    if let Some(original) = originals.get(&digest) {
+                        out.verbose(format!(
+                            "\t{}: duplicate of {}",
+                            image_info.path.display(),
+                            original.display()
+                        ));
+                        duplicates += 1;
+                        continue;
+                    }
+                    originals.insert(digest, image_info.path.clone());
+                }
+                out.verbose(format!(
+                    "\t{}: image ({:?})",
+                    image_info.path.display(),
+                    image_info.format
+                ));
+                imgs.push(image_info);
+            }
+            Entry::NonImage(path, reason) => {
+                out.verbose(format!("\t{}: non-image ({reason})", path.display()));
+                non_imgs.push((path, reason));
+            }
+            Entry::Unreadable(path, err) => {
+                anomaly(format!("{err:#}"), options, out)?;
+                out.verbose(format!("\t{}: non-image ({UNREADABLE})", path.display()));
+                non_imgs.push((path, UNREADABLE));
+            }
+            Entry::Excluded(path) => {
+                out.verbose(format!("\t{}: excluded (copied as-is)", path.display()));
+                excluded.push(path);
+            }
+            Entry::Skipped(path) => {
+                out.verbose(format!(
+                    "\t{}: skipped (hidden/system file)",
+                    path.display()
+                ));
+                skipped += 1;
+            }
+            Entry::Ignored(path) => {
+                out.verbose(format!("\t{}: ignored", path.display()));
+                ignored += 1;
+            }
+        }
+    }
+    if skipped > 0 {
+        out.info(format!("Skipped {skipped} hidden/system files"));
+    }
+    if ignored > 0 {
+        out.info(format!("Ignored {ignored} files"));
+    }
+    if duplicates > 0 {
+        out.info(format!("Collapsed {duplicates} duplicate images"));
+    }
+    if !small.is_empty() {
+        anomaly(
+            format!(
+                "Dropping {} images smaller than {}px",
+                small.len(),
+                options.min_dimension.unwrap_or_default()
+            ),
+            options,
+            out,
+        )?;
+        for image in small {
+            out.info(format!("\t{image}"));
+        }
+    }
+    // The sorts are stable, so ties and images lacking the sort key stay in file name order.
+    match options.sort_by {
+        SortBy::Lexical | SortBy::Natural => {}
+        SortBy::Size => imgs.sort_by_key(|img| img.size),
+        SortBy::Mtime => {
+            imgs.sort_by(|a, b| missing_last(a.modified.as_ref(), b.modified.as_ref()));
+            let missing = imgs.iter().filter(|img| img.modified.is_none()).count();
+            if missing > 0 {
+                anomaly(
+                    format!("{missing} images have no modification time, sorting them last"),
+                    options,
+                    out,
+                )?;
+            }
+        }
+        SortBy::ExifDate => {
+            imgs.sort_by(|a, b| missing_last(a.capture_time.as_ref(), b.capture_time.as_ref()));
+            let missing = imgs.iter().filter(|img| img.capture_time.is_none()).count();
+            if missing > 0 {
+                anomaly(
+                    format!("{missing} images have no EXIF capture time, sorting them last"),
+                    options,
+                    out,
+                )?;
+            }
+        }
+    }
+    order_pages(&mut imgs, dir, options, out)?;
+    let cmyk = imgs
+        .iter()
+        .filter(|img| img.cmyk)
+        .map(|img| img.path.display().to_string())
+        .collect::<Vec<_>>();
+    if !cmyk.is_empty() && converts_cmyk(options) {
+        out.info(format!("Converting {} CMYK JPEG images to RGB", cmyk.len()));
+    } else if !cmyk.is_empty() {
+        anomaly(
+            format!(
+                "Found {} CMYK JPEG images, which many readers display with wrong colors. Use --fix-cmyk to convert them to RGB",
+                cmyk.len()
+            ),
+            options,
+            out,
+        )?;
+        for path in cmyk {
+            out.info(format!("\t{path}"));
+        }
+    }
+    let normalize = imgs.iter().filter(|img| img.normalize).count();
+    if normalize > 0 {
+        out.info(format!(
+            "Converting {normalize} 16-bit or paletted PNG images to 8-bit RGB or RGBA"
+        ));
+    }
+    let animated = imgs.iter().filter(|img| img.animated).count();
+    if animated > 0 && options.explode_frames {
+        out.info(format!(
+            "Adding {} frames of {animated} animated images as separate pages",
+            imgs.iter()
+                .filter(|img| img.animated)
+                .map(|img| img.frames)
+                .sum::<usize>()
+        ));
+    } else if animated > 0 {
+        anomaly(
+            format!("Keeping only the first frame of {animated} animated images"),
+            options,
+            out,
+        )?;
+    }
+    if options.reverse {
+        imgs.reverse();
+    }
+    if options.dedupe_adjacent {
+        imgs = dedupe_adjacent(imgs, options, out);
+    }
+    if options.check_dimensions {
+        check_dimensions(&imgs, options, out)?;
+    }
+
+    Ok(Scan {
+        images: imgs,
+        non_images: non_imgs,
+        excluded,
+    })
+}
+
+/// Blends an image with transparency onto a white background.
+///
+/// Grayscale images stay grayscale.
+fn flatten(image: &DynamicImage) -> DynamicImage {
+    let mut rgba = image.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let alpha = u16::from(pixel[3]);
+        for channel in &mut pixel.0[..3] {
+            // The blended value never exceeds 255.
+            *channel = ((u16::from(*channel) * alpha + 255 * (255 - alpha) + 127) / 255) as u8;
+        }
+    }
+    let rgb = DynamicImage::ImageRgba8(rgba).to_rgb8();
+    if image.color().has_color() {
+        DynamicImage::ImageRgb8(rgb)
+    } else {
+        DynamicImage::ImageLuma8(DynamicImage::ImageRgb8(rgb).to_luma8())
+    }
+}
+
+/// Encodes an image in the given format.
+///
+/// JPEG, WebP and AVIF images are encoded lossily with `quality`, AVIF images additionally using `avif_speed`. WebP
+/// images are encoded losslessly instead if `webp_lossless` is true. JPEG images are encoded with the chroma
+/// subsampling given by `jpeg_subsampling`, defaulting to none at all so that colored text keeps crisp edges, after
+/// blending any transparency onto white as described in `flatten`. AVIF encoding fails if support for it has not been
+/// compiled in. Errors are propagated.
+fn encode_image(
+    image: &DynamicImage,
+    format: ImageFormat,
+    options: &CbzOptions,
+) -> Result<Vec<u8>> {
+    let quality = options.quality;
+    if format == ImageFormat::WebP {
+        let image = if image.color().has_alpha() {
+            DynamicImage::ImageRgba8(image.to_rgba8())
+        } else {
+            DynamicImage::ImageRgb8(image.to_rgb8())
+        };
+        let encoder = webp::Encoder::from_image(&image)
+            .map_err(|e| anyhow::anyhow!("Failed to create WebP encoder: {e}"))?;
+        let encoded = if options.webp_lossless {
+            encoder.encode_lossless()
+        } else {
+            encoder.encode(f32::from(quality))
+        };
+        return Ok(encoded.to_vec());
+    }
+
+    let mut buf = io::Cursor::new(Vec::new());
+    if format == ImageFormat::Avif {
+        #[cfg(feature = "avif")]
+        {
+            let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                &mut buf,
+                options.avif_speed,
+                quality,
+            );
+            if image.color().has_alpha() {
+                DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(encoder)
+            } else {
+                DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)
+            }
+            .context("Failed to encode image as Avif")?;
+        }
+        #[cfg(not(feature = "avif"))]
+        anyhow::bail!("AVIF encoding is not supported by this build");
+    } else if format == ImageFormat::Jpeg {
+        // The encoder only accepts qualities from 1.
+        let mut encoder = jpeg_encoder::Encoder::new(&mut buf, quality.max(1));
+        encoder.set_sampling_factor(
+            options
+                .jpeg_subsampling
+                .unwrap_or(JpegSubsampling::S444)
+                .sampling_factor(),
+        );
+        let (Ok(width), Ok(height)) = (u16::try_from(image.width()), u16::try_from(image.height()))
+        else {
+            anyhow::bail!(
+                "Image of {}x{} pixels is too large to encode as Jpeg",
+                image.width(),
+                image.height()
+            );
+        };
+        // JPEG has no transparency, so transparent pixels are shown as the white of a page.
+        let image = if image.color().has_alpha() {
+            &flatten(image)
+        } else {
+            image
+        };
+        if image.color().has_color() {
+            encoder.encode(
+                image.to_rgb8().as_raw(),
+                width,
+                height,
+                jpeg_encoder::ColorType::Rgb,
+            )
+        } else {
+            encoder.encode(
+                image.to_luma8().as_raw(),
+                width,
+                height,
+                jpeg_encoder::ColorType::Luma,
+            )
+        }
+        .context("Failed to encode image as Jpeg")?;
+    } else {
+        image
+            .write_to(&mut buf, format)
+            .with_context(|| format!("Failed to encode image as {format:?}"))?;
+    }
+    Ok(buf.into_inner())
+}
+
+/// Checks whether an image of the given dimensions exceeds `max_width` or `max_height`.
+fn exceeds_bounds(width: u32, height: u32, options: &CbzOptions) -> bool {
+    options.max_width.is_some_and(|max_width| width > max_width)
+        || options
+            .max_height
+            .is_some_and(|max_height| height > max_height)
+}
+
+/// Checks whether an image of the given dimensions is a spread that should be split.
+///
+/// Landscape images are split if `split_spreads` is true, except for the `first` image if `no_split_first` is true.
+fn is_spread(width: u32, height: u32, first: bool, options: &CbzOptions) -> bool {
+    options.split_spreads && width > height && !(first && options.no_split_first)
+}
+
+/// Checks whether an image carries any metadata.
+///
+/// Looks for EXIF, ICC, XMP and IPTC data as well as PNG text and time chunks. Errors are propagated.
+fn has_metadata(decoder: &mut impl ImageDecoder, buf: &[u8], format: ImageFormat) -> Result<bool> {
+    if decoder.exif_metadata()?.is_some()
+        || decoder.icc_profile()?.is_some()
+        || decoder.xmp_metadata()?.is_some()
+        || decoder.iptc_metadata()?.is_some()
+    {
+        return Ok(true);
+    }
+
+    if format == ImageFormat::Png {
+        // Walk the chunks following the 8 byte signature. Each chunk is its length, type, data and CRC.
+        let mut pos = 8;
+        while let Some(header) = buf.get(pos..pos + 8) {
+            let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            if matches!(&header[4..], b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+                return Ok(true);
+            }
+            pos += len + 12;
+        }
+    }
+
+    Ok(false)
+}
+
+/// Returns the dimensions of an image along with its orientation.
+///
+/// The orientation is only read if `auto_orient` is true, in which case the dimensions are those of the upright image.
+/// Errors are propagated.
+fn oriented_dimensions(
+    decoder: &mut impl ImageDecoder,
+    options: &CbzOptions,
+) -> Result<((u32, u32), Orientation)> {
+    let (width, height) = decoder.dimensions();
+    if !options.auto_orient {
+        return Ok(((width, height), Orientation::NoTransforms));
+    }
+
+    let orientation = decoder.orientation()?;
+    let dimensions = match orientation {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    };
+    Ok((dimensions, orientation))
+}
+
+/// Returns the number of pages an image is written as.
+///
+/// Spreads are written as two pages as described in `is_spread`, with `first` telling whether `img` is the first image.
+/// All other images are written as one page. Each frame of an animation whose frames are added separately is written as
+/// such. Only the image header is read. Errors are propagated with added context.
+fn page_count(input: &Input, img: &ImageInfo, first: bool, options: &CbzOptions) -> Result<usize> {
+    if !options.split_spreads {
+        return Ok(img.frames);
+    }
+
+    let mut decoder = ImageReader::with_format(input.reader(&img.path)?, img.format)
+        .into_decoder()
+        .with_context(|| format!("Failed to read header of {}", img.path.display()))?;
+    let ((width, height), _) = oriented_dimensions(&mut decoder, options)
+        .with_context(|| format!("Failed to read header of {}", img.path.display()))?;
+    Ok(if is_spread(width, height, first, options) {
+        2 * img.frames
+    } else {
+        img.frames
+    })
+}
+
+/// Returns the content region of an image with uniform borders removed.
+///
+/// Each edge is trimmed while its outermost row or column only contains pixels within `tolerance` of the pixel in the
+/// middle of that edge, in every channel. At most `MAX_TRIM_FRACTION` of the width or height is trimmed from each edge.
+/// Returns None if there is nothing to trim, or if the content region collapses to less than `MIN_CONTENT_FRACTION` of
+/// the width or height, as is the case for blank pages.
+fn trim_bounds(image: &DynamicImage, tolerance: u8) -> Option<(u32, u32, u32, u32)> {
+    let pixels = image.to_rgba8();
+    let (width, height) = pixels.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let similar = |x, y, reference: Rgba<u8>| {
+        pixels
+            .get_pixel(x, y)
+            .0
+            .iter()
+            .zip(reference.0)
+            .all(|(&a, b)| a.abs_diff(b) <= tolerance)
+    };
+    let uniform_row = |y, reference| (0..width).all(|x| similar(x, y, reference));
+
+    let reference = *pixels.get_pixel(width / 2, 0);
+    let top = (0..height)
+        .take_while(|&y| uniform_row(y, reference))
+        .count() as u32;
+    let reference = *pixels.get_pixel(width / 2, height - 1);
+    let bottom = (top..height)
+        .rev()
+        .take_while(|&y| uniform_row(y, reference))
+        .count() as u32;
+    let content = |size: u32, start: u32, end: u32| f64::from(size - start - end);
+    if content(height, top, bottom) < f64::from(height) * MIN_CONTENT_FRACTION {
+        return None;
+    }
+    let uniform_column = |x, reference| (top..height - bottom).all(|y| similar(x, y, reference));
+    let reference = *pixels.get_pixel(0, height / 2);
+    let left = (0..width)
+        .take_while(|&x| uniform_column(x, reference))
+        .count() as u32;
+    let reference = *pixels.get_pixel(width - 1, height / 2);
+    let right = (left..width)
+        .rev()
+        .take_while(|&x| uniform_column(x, reference))
+        .count() as u32;
+
+    if content(width, left, right) < f64::from(width) * MIN_CONTENT_FRACTION {
+        return None;
+    }
+
+    // Truncation is intended, the cap only needs to be approximate.
+    let cap = |size: u32, trim: u32| trim.min((f64::from(size) * MAX_TRIM_FRACTION) as u32);
+    let (top, bottom) = (cap(height, top), cap(height, bottom));
+    let (left, right) = (cap(width, left), cap(width, right));
+    if top == 0 && bottom == 0 && left == 0 && right == 0 {
+        return None;
+    }
+
+    Some((left, top, width - left - right, height - top - bottom))
+}
+
+/// Converts an image to 8-bit grayscale, keeping any alpha channel.
+///
+/// The gray values are reduced to `gray_levels` evenly spaced levels if provided. If `dither` is `floyd-steinberg` the
+/// rounding error of every pixel is diffused onto its neighbors, which avoids banding in gradients, even when keeping
+/// all 256 levels of images with more precision than that.
+fn to_grayscale(image: &DynamicImage, options: &CbzOptions) -> DynamicImage {
+    let has_alpha = image.color().has_alpha();
+    if options.gray_levels.is_none() && options.dither == Dither::None {
+        return if has_alpha {
+            DynamicImage::ImageLumaA8(image.to_luma_alpha8())
+        } else {
+            DynamicImage::ImageLuma8(image.to_luma8())
+        };
+    }
+
+    let step = 255.0 / f32::from(options.gray_levels.unwrap_or(256) - 1);
+    let quantize = |value: f32| ((value / step).round() * step).clamp(0.0, 255.0);
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut values = image
+        .to_luma32f()
+        .into_raw()
+        .into_iter()
+        .map(|value| value * 255.0)
+        .collect::<Vec<_>>();
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let value = values[i];
+            let quantized = quantize(value);
+            values[i] = quantized;
+            if options.dither == Dither::FloydSteinberg {
+                let error = value - quantized;
+                let mut diffuse = |x: usize, y: usize, weight: f32| {
+                    if x < width && y < height {
+                        values[y * width + x] += error * weight;
+                    }
+                };
+                diffuse(x + 1, y, 7.0 / 16.0);
+                if x > 0 {
+                    diffuse(x - 1, y + 1, 3.0 / 16.0);
+                }
+                diffuse(x, y + 1, 5.0 / 16.0);
+                diffuse(x + 1, y + 1, 1.0 / 16.0);
+            }
+        }
+    }
+    // Quantized values are whole numbers within range, so the conversion is exact.
+    let luma = values.into_iter().map(|value| value as u8);
+    if has_alpha {
+        let mut gray = image.to_luma_alpha8();
+        for (pixel, luma) in gray.pixels_mut().zip(luma) {
+            pixel.0[0] = luma;
+        }
+        DynamicImage::ImageLumaA8(gray)
+    } else {
+        let gray = image::GrayImage::from_raw(image.width(), image.height(), luma.collect())
+            .expect("Buffer matches the image dimensions");
+        DynamicImage::ImageLuma8(gray)
+    }
+}
+
+/// Checks whether any transformation which may re-encode images is requested.
+fn transforms_images(options: &CbzOptions) -> bool {
+    options.convert.is_some()
+        || options.max_width.is_some()
+        || options.max_height.is_some()
+        || options.split_spreads
+        || options.grayscale
+        || options.force_grayscale
+        || options.trim
+        || options.auto_orient
+        || options.strip_metadata
+}
+
+/// Checks if CMYK JPEG images are converted to RGB.
+///
+/// This is the case if `fix_cmyk` is true, if images are converted using `convert`, and for PDF output which only
+/// embeds RGB and grayscale JPEG images as they are.
+fn converts_cmyk(options: &CbzOptions) -> bool {
+    options.fix_cmyk || options.convert.is_some() || matches!(options.format, ArchiveFormat::Pdf)
+}
+
+/// Checks if `img` has to be re-encoded even if no transformation is requested.
+///
+/// This is the case for animated images, CMYK JPEG images if `converts_cmyk` is true, and PNG images which need
+/// normalizing.
+fn reencodes(img: &ImageInfo, options: &CbzOptions) -> bool {
+    img.animated || (img.cmyk && converts_cmyk(options)) || img.normalize
+}
+
+/// Applies all requested transformations to an image.
+///
+/// Takes the raw bytes of `img` and returns the pages to be written, which are several if it is split or its frames
+/// are exploded. The bytes are returned untouched as a single page if nothing needs to change. Otherwise the image is
+/// decoded, transformed as described for each option and re-encoded, without carrying over any metadata. `first`
+/// tells whether `img` is the first image. Errors are propagated with added context.
+fn transform_image(
+    img: &ImageInfo,
+    buf: Vec<u8>,
+    first: bool,
+    options: &CbzOptions,
+) -> Result<Transformed> {
+    let format = options.convert.map_or(img.format, ConvertFormat::format);
+    let (mut resize, mut split, mut grayscale) = (false, false, options.force_grayscale);
+    let mut orientation = Orientation::NoTransforms;
+    let mut strip = false;
+    if options.max_width.is_some()
+        || options.max_height.is_some()
+        || options.split_spreads
+        || options.grayscale
+        || options.auto_orient
+        || options.strip_metadata
+    {
+        let mut decoder = ImageReader::with_format(io::Cursor::new(&buf), img.format)
+            .into_decoder()
+            .with_context(|| format!("Failed to read header of {}", img.path.display()))?;
+        let ((width, height), image_orientation) = oriented_dimensions(&mut decoder, options)
+            .with_context(|| format!("Failed to read header of {}", img.path.display()))?;
+        orientation = image_orientation;
+        split = is_spread(width, height, first, options);
+        resize = exceeds_bounds(width.div_ceil(if split { 2 } else { 1 }), height, options);
+        grayscale |= options.grayscale && decoder.color_type().has_color();
+        strip = options.strip_metadata
+            && has_metadata(&mut decoder, &buf, img.format)
+                .with_context(|| format!("Failed to read metadata of {}", img.path.display()))?;
+    }
+    let orient = orientation != Orientation::NoTransforms;
+    let fix_cmyk = img.cmyk && converts_cmyk(options);
+    let unchanged = format == img.format
+        && !img.animated
+        && !fix_cmyk
+        && !img.normalize
+        && !orient
+        && !resize
+        && !split
+        && !grayscale
+        && !strip;
+    if unchanged && !options.trim {
+        return Ok(Transformed {
+            pages: vec![(buf, img.format)],
+            stripped: false,
+        });
+    }
+
+    let frames = if img.frames > 1 {
+        decode_frames(img, &buf)?
+    } else {
+        vec![
+            image::load_from_memory_with_format(&buf, img.format)
+                .with_context(|| format!("Failed to decode {}", img.path.display()))?,
+        ]
+    };
+    let mut pages = Vec::new();
+    for mut image in frames {
+        if img.normalize {
+            image = if image.color().has_alpha() {
+                DynamicImage::ImageRgba8(image.to_rgba8())
+            } else {
+                DynamicImage::ImageRgb8(image.to_rgb8())
+            };
+        }
+        image.apply_orientation(orientation);
+        if grayscale {
+            image = to_grayscale(&image, options);
+        }
+        if options.trim {
+            match trim_bounds(&image, options.trim_tolerance) {
+                Some((x, y, width, height)) => image = image.crop_imm(x, y, width, height),
+                None if unchanged => {
+                    return Ok(Transformed {
+                        pages: vec![(buf, img.format)],
+                        stripped: false,
+                    });
+                }
+                None => {}
+            }
+        }
+        let halves = if split {
+            let half = image.width() / 2;
+            let left = image.crop_imm(0, 0, half, image.height());
+            let right = image.crop_imm(half, 0, image.width() - half, image.height());
+            match options.split_direction {
+                SplitDirection::Ltr => vec![left, right],
+                SplitDirection::Rtl => vec![right, left],
+            }
+        } else {
+            vec![image]
+        };
+
+        for mut page in halves {
+            if exceeds_bounds(page.width(), page.height(), options) {
+                page = page.resize(
+                    options.max_width.unwrap_or(u32::MAX),
+                    options.max_height.unwrap_or(u32::MAX),
+                    options.resize_filter.filter_type(),
+                );
+            }
+            let buf = encode_image(&page, format, options)
+                .with_context(|| format!("Failed to encode {}", img.path.display()))?;
+            pages.push((buf, format));
+        }
+    }
+
+    Ok(Transformed {
+        pages,
+        stripped: strip,
+    })
+}
+
+/// Index of an image skipped while writing, along with the error which occurred.
+type SkippedImage = (usize, anyhow::Error);
+
+/// Result of transforming an image.
+struct Transformed {
+    /// Pages to be written, as bytes along with their format.
+    pages: Vec<(Vec<u8>, ImageFormat)>,
+    /// Whether metadata was stripped from the image.
+    stripped: bool,
+}
+
+/// Sanitizes a file name for use as an archive entry.
+///
+/// The name is normalized to Unicode NFC, control characters and `RESERVED_CHARS` are replaced by underscores, and
+/// whitespace and dots are trimmed from both ends of the stem. An empty stem is replaced by an underscore.
+fn sanitize_name(name: &str) -> String {
+    let name = name
+        .nfc()
+        .map(|c| {
+            if c.is_control() || RESERVED_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name.as_str(), None),
+    };
+    let stem = stem.trim_matches(|c: char| c.is_whitespace() || c == '.');
+    let stem = if stem.is_empty() { "_" } else { stem };
+
+    match extension {
+        Some(extension) => format!("{stem}.{extension}"),
+        None => stem.to_string(),
+    }
+}
+
+/// Makes `name` unique among the `taken` names and adds it to them.
+///
+/// Names are compared ignoring case, as archives are often extracted to case-insensitive file systems. A taken name is
+/// given the smallest numeric suffix `_2`, `_3`, ... before the extension which makes it unique, so that collisions are
+/// resolved deterministically in page order.
+fn unique_name(name: String, taken: &mut HashSet<String>) -> String {
+    if taken.insert(name.to_lowercase()) {
+        return name;
+    }
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) => (stem, format!(".{extension}")),
+        None => (name.as_str(), String::new()),
+    };
+    let name = (2..)
+        .map(|n| format!("{stem}_{n}{extension}"))
+        .find(|name| !taken.contains(&name.to_lowercase()))
+        .expect("Suffixes are unbounded");
+    taken.insert(name.to_lowercase());
+    name
+}
+
+/// Returns the number of the last of `count` pages, counting from `first`.
+fn last_page_number(first: usize, count: usize) -> usize {
+    (first + count).saturating_sub(1)
+}
+
+/// Orders `imgs` as listed in `page_order_file`, if provided.
+///
+/// Every line names an image by its file name or its path relative to `dir`, like `cover`. Empty lines and lines
+/// starting with `#` are skipped. Images which are not listed are an error, unless `append_unlisted` is true in which
+/// case they are added after the listed ones in their current order. Fails if the file can't be read, or naming all
+/// problems if a line matches no image or several images, or if an image is listed more than once.
+fn order_pages(
+    imgs: &mut Vec<ImageInfo>,
+    dir: &Path,
+    options: &CbzOptions,
+    out: &mut Output,
+) -> Result<()> {
+    let Some(order_file) = &options.page_order_file else {
+        return Ok(());
+    };
+    let list = fs::read_to_string(order_file)
+        .with_context(|| format!("Failed to read page order file {}", order_file.display()))?;
+
+    let named = |img: &ImageInfo, name: &Path| {
+        img.path.file_name() == Some(name.as_os_str())
+            || img.path.strip_prefix(dir).is_ok_and(|path| path == name)
+    };
+    // Listed images are taken out of `unlisted`, so that listing one twice can be told apart from a missing one.
+    let mut unlisted = std::mem::take(imgs)
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+    let mut listed = Vec::new();
+    let mut problems = Vec::new();
+    for line in list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        let name = Path::new(line);
+        let matching = unlisted
+            .iter()
+            .enumerate()
+            .filter(|(_, img)| img.as_ref().is_some_and(|img| named(img, name)))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        match matching[..] {
+            [i] => listed.extend(unlisted[i].take()),
+            [] if listed.iter().any(|img| named(img, name)) => {
+                problems.push(format!("{line} is listed more than once"));
+            }
+            [] => problems.push(format!("{line} matches no image")),
+            _ => problems.push(format!(
+                "{line} matches {} images, give its path relative to {} instead",
+                matching.len(),
+                dir.display()
+            )),
+        }
+    }
+    let unlisted = unlisted.into_iter().flatten().collect::<Vec<_>>();
+    if !unlisted.is_empty() && !options.append_unlisted {
+        problems.push(format!(
+            "{} images are not listed, use --append-unlisted to add them after the listed ones: {}",
+            unlisted.len(),
+            unlisted
+                .iter()
+                .map(|img| img.path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "Page order file {} doesn't match the images in {}: {}",
+            order_file.display(),
+            dir.display(),
+            problems.join("; ")
+        );
+    }
+
+    if !unlisted.is_empty() {
+        out.info(format!(
+            "Adding {} images not listed in {} after the listed ones",
+            unlisted.len(),
+            order_file.display()
+        ));
+    }
+    listed.extend(unlisted);
+    *imgs = listed;
+
+    Ok(())
+}
+
+/// Moves the image given by `cover` to the front of `imgs`, if provided.
+///
+/// The cover is given by its file name or its path relative to `dir`. Fails if it is not among `imgs`.
+fn move_cover(imgs: &mut Vec<ImageInfo>, dir: &Path, options: &CbzOptions) -> Result<()> {
+    if let Some(cover) = &options.cover {
+        let idx = imgs
+            .iter()
+            .position(|img| {
+                img.path.file_name() == Some(cover.as_os_str())
+                    || img.path.strip_prefix(dir).is_ok_and(|path| path == cover)
+            })
+            .with_context(|| {
+                format!(
+                    "Cover {} not found among images in {}",
+                    cover.display(),
+                    dir.display()
+                )
+            })?;
+        let img = imgs.remove(idx);
+        imgs.insert(0, img);
+    }
+
+    Ok(())
+}
+
+/// Checks that `pad_width` fits the last of `count` page numbers counting from `first`, if pages are renamed.
+fn check_pad_width(first: usize, count: usize, options: &CbzOptions) -> Result<()> {
+    if let Some(pad_width) = options.pad_width
+        && !options.no_rename
+        && pad_width < last_page_number(first, count).to_string().len()
+    {
+        anyhow::bail!(
+            "--pad-width {pad_width} is too small for page number {}",
+            last_page_number(first, count)
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks that a `series` is known for `dir` if `name_template` uses it.
+fn check_series(series: Option<&str>, dir: &Path, options: &CbzOptions) -> Result<()> {
+    if options
+        .name_template
+        .as_ref()
+        .is_some_and(NameTemplate::uses_series)
+        && series.is_none()
+    {
+        anyhow::bail!(
+            "--name-template uses {{series}}, but no series is known for {}",
+            dir.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Names shared by all pages of an archive.
+#[derive(Clone)]
+struct Naming<'a> {
+    /// Number of the first page, which is one less than `start_number` if a cover file is added in front.
+    first: usize,
+    /// Total number of pages.
+    count: usize,
+    /// Series of the comic, if known.
+    series: Option<&'a str>,
+    /// Names already given to pages, as tracked by `unique_name`.
+    taken: HashSet<String>,
+    /// Number of kept file names whose extension has been corrected.
+    fixed: usize,
+    /// Number of pages already in the archive being appended to, which new pages are numbered after.
+    offset: usize,
+}
+
+/// Checks if the extension of `file_name` is one of the extensions of `format`, ignoring case.
+fn has_extension(file_name: &Path, format: ImageFormat) -> bool {
+    file_name.extension().is_some_and(|extension| {
+        format
+            .extensions_str()
+            .iter()
+            .any(|ext| extension.eq_ignore_ascii_case(ext))
+    })
+}
+
+/// Returns the archive entry name for an image.
+///
+/// The page at `idx` is numbered, or keeps its original file name if `no_rename` is true, as described for the naming
+/// options, with `part` counting the pages of a split image from 1. The extension matches `format`, the format the page
+/// is written in, except for kept file names of unconverted pages, and corrected extensions are counted in `naming`.
+/// Names are made unique among the pages named so far where needed, whereas other collisions are rejected beforehand
+/// by `check_collisions`.
+fn entry_name(
+    img: &ImageInfo,
+    idx: usize,
+    part: Option<usize>,
+    format: ImageFormat,
+    options: &CbzOptions,
+    naming: &mut Naming,
+) -> String {
+    let file_name = Path::new(img.path.file_name().unwrap_or_default());
+    if !options.no_rename {
+        let default = NameTemplate::default();
+        let template = options.name_template.as_ref().unwrap_or(&default);
+        let name = template.expand(&template::Values {
+            number: naming.first + naming.offset + idx,
+            width: options.pad_width.unwrap_or_else(|| {
+                max(
+                    last_page_number(naming.first, naming.offset + naming.count)
+                        .to_string()
+                        .len(),
+                    2,
+                )
+            }),
+            orig: &file_name.file_stem().unwrap_or_default().to_string_lossy(),
+            ext: format.extensions_str()[0],
+            series: naming.series,
+        });
+        return unique_name(name, &mut naming.taken);
+    }
+
+    // Names which aren't valid UTF-8 have the invalid bytes replaced, so different names can end up the same.
+    let lossy = file_name.to_str().is_none();
+    let name = if let Some(part) = part {
+        format!(
+            "{}_{}.{}",
+            file_name.file_stem().unwrap_or_default().to_string_lossy(),
+            part + 1,
+            format.extensions_str()[0]
+        )
+    } else if format == img.format && (!options.fix_extensions || has_extension(file_name, format))
+    {
+        file_name.to_string_lossy().into_owned()
+    } else {
+        naming.fixed += usize::from(format == img.format);
+        file_name
+            .with_extension(format.extensions_str()[0])
+            .to_string_lossy()
+            .into_owned()
+    };
+    if options.sanitize_names {
+        unique_name(sanitize_name(&name), &mut naming.taken)
+    } else if options.unique_names || lossy {
+        unique_name(name, &mut naming.taken)
+    } else {
+        name
+    }
+}
+
+/// Returns the entry names of the pages of every image, as given by `entry_name`.
+///
+/// Pages are assumed to be written in the format they are converted to, if any.
+fn planned_names(
+    imgs: &[ImageInfo],
+    page_counts: &[usize],
+    options: &CbzOptions,
+    naming: &mut Naming,
+) -> Vec<Vec<String>> {
+    let mut idx = 0;
+    imgs.iter()
+        .zip(page_counts)
+        .map(|(img, &pages)| {
+            let format = options.convert.map_or(img.format, ConvertFormat::format);
+            let names = (0..pages)
+                .map(|part| {
+                    let part = (pages > 1).then_some(part);
+                    entry_name(img, idx + part.unwrap_or(0), part, format, options, naming)
+                })
+                .collect();
+            idx += pages;
+            names
+        })
+        .collect()
+}
+
+/// Checks that no two pages are given the same entry name.
+///
+/// Only kept file names can collide, as other names are made unique by `entry_name`. Names are compared ignoring case
+/// like in `unique_name`, also against the names already taken in `naming`. Fails naming all collisions, as a page
+/// would otherwise shadow another one when extracted.
+fn check_collisions(
+    imgs: &[ImageInfo],
+    page_counts: &[usize],
+    options: &CbzOptions,
+    naming: &Naming,
+) -> Result<()> {
+    if !options.no_rename || options.sanitize_names || options.unique_names {
+        return Ok(());
+    }
+
+    let mut sources: HashMap<String, Option<&Path>> = naming
+        .taken
+        .iter()
+        .map(|name| (name.clone(), None))
+        .collect();
+    let names = planned_names(imgs, page_counts, options, &mut naming.clone());
+    let mut collisions = Vec::new();
+    for (img, names) in imgs.iter().zip(names) {
+        for name in names {
+            match sources.entry(name.to_lowercase()) {
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert(Some(&img.path));
+                }
+                hash_map::Entry::Occupied(entry) => collisions.push(match entry.get() {
+                    Some(path) => {
+                        format!("{} and {} as {name}", path.display(), img.path.display())
+                    }
+                    None => format!("{} and an existing entry as {name}", img.path.display()),
+                }),
+            }
+        }
+    }
+    if !collisions.is_empty() {
+        anyhow::bail!(
+            "{} kept file names collide, use --unique-names to add suffixes: {}",
+            collisions.len(),
+            collisions.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints what `create_cbz` would do without writing anything.
+///
+/// Lists the output path, the generated files, the planned names of all pages as given by `naming` and the excluded
+/// files which would be copied as-is.
+#[expect(
+    clippy::too_many_arguments,
+    reason = "The plan covers everything create_cbz has worked out"
+)]
+fn print_plan(
+    archive_path: &Path,
+    imgs: &[ImageInfo],
+    page_counts: &[usize],
+    excluded: &[PathBuf],
+    comic_info: Option<&ComicInfo>,
+    toc: Option<&str>,
+    mut naming: Naming,
+    options: &CbzOptions,
+    out: &mut Output,
+) {
+    if options.append && archive_path.exists() {
+        out.info(format!(
+            "Would append to {}, after its {} pages",
+            archive_path.display(),
+            naming.offset
+        ));
+    } else {
+        out.info(format!(
+            "Would create {}{}",
+            archive_path.display(),
+            if archive_path.exists() {
+                " (overwriting existing file)"
+            } else {
+                ""
+            }
+        ));
+    }
+    if let Some(max_size) = options.max_archive_size {
+        out.info(format!(
+            "Would split into parts of at most {max_size} bytes if larger"
+        ));
+    }
+    if let Some(comic_info) = comic_info {
+        out.info(format!("Would generate {}", comic_info::FILE_NAME));
+        if !comic_info.bookmarks.is_empty() {
+            out.info(format!(
+                "Would bookmark {} chapters",
+                comic_info.bookmarks.len()
+            ));
+            for (page, name) in &comic_info.bookmarks {
+                out.info(format!("\t{name} (from page {})", page + 1));
+            }
+        }
+    }
+    if let Some(toc) = toc {
+        out.info(format!("Would generate {TOC_FILE}"));
+        for line in toc.lines() {
+            out.info(format!("\t{line}"));
+        }
+    }
+    if options.manifest {
+        out.info(format!("Would generate {MANIFEST_FILE}"));
+    }
+    if options.verify_output {
+        out.info("Would verify the output");
+    }
+    out.info(format!("Would add {} pages", naming.count));
+    let names = planned_names(imgs, page_counts, options, &mut naming);
+    for (img, names) in imgs.iter().zip(names) {
+        out.info(format!("\t{} -> {}", img.path.display(), names.join(", ")));
+    }
+    if !excluded.is_empty() {
+        out.info(format!("Would add {} excluded files as-is", excluded.len()));
+        for path in excluded {
+            out.info(format!("\t{}", path.display()));
+        }
+    }
+}
+
+/// Returns the modification time to give the archive entries created from `path` inside `input`.
+///
+/// Returns None unless `preserve_mtime` is true. If the modification time is unavailable None is returned, if it is out
+/// of range for `format` the clamped time is returned. Either is reported as an anomaly as described in `anomaly`.
+fn source_mtime(
+    input: &Input,
+    path: &Path,
+    options: &CbzOptions,
+    out: &mut Output,
+) -> Result<Option<SystemTime>> {
+    if !options.preserve_mtime {
+        return Ok(None);
+    }
+
+    match input.modified(path) {
+        Ok(mtime) => {
+            let clamped = options.format.clamp_mtime(mtime);
+            if clamped != mtime {
+                anomaly(
+                    format!(
+                        "Modification time of {} is out of range for {}, clamping",
+                        path.display(),
+                        options.format
+                    ),
+                    options,
+                    out,
+                )?;
+            }
+            Ok(Some(clamped))
+        }
+        Err(e) => {
+            anomaly(
+                format!(
+                    "Failed to read modification time of {}, using default: {e}",
+                    path.display()
+                ),
+                options,
+                out,
+            )?;
+            Ok(None)
+        }
+    }
+}
+
+/// Returns the newest modification time of `root`, `paths` and, if `descend` is true, everything inside them.
+///
+/// Directories count as well, as removing or renaming a file only changes the modification time of its directory.
+/// `root` and `paths` are always followed, only symlinked directories found inside them are not descended into. Broken
+/// symlinks count by the modification time of the link itself. Errors are propagated with added context.
+fn newest_mtime_of(root: &Path, paths: &[PathBuf], descend: bool) -> Result<SystemTime> {
+    let mut pending = vec![(root.to_path_buf(), true)];
+    pending.extend(paths.iter().map(|path| (path.clone(), true)));
+    let mut newest = SystemTime::UNIX_EPOCH;
+    while let Some((path, top_level)) = pending.pop() {
+        let metadata = fs::metadata(&path)
+            .or_else(|_| fs::symlink_metadata(&path))
+            .with_context(|| format!("Failed to read metadata of {}", path.display()))?;
+        let modified = metadata
+            .modified()
+            .with_context(|| format!("Failed to read modification time of {}", path.display()))?;
+        newest = newest.max(modified);
+        if metadata.is_dir() && descend && (top_level || !path.is_symlink()) {
+            for entry in fs::read_dir(&path)
+                .with_context(|| format!("Failed to read directory {}", path.display()))?
+            {
+                let entry =
+                    entry.with_context(|| format!("Error while reading {}", path.display()))?;
+                pending.push((entry.path(), false));
+            }
+        }
+    }
+
+    Ok(newest)
+}
+
+/// Returns the newest modification time of the files and directories read from `input`.
+///
+/// A directory counts along with everything inside it, as described in `newest_mtime_of`. A list file counts along with
+/// the paths listed in it, whereas an archive is represented by the modification time of the archive itself, and merged
+/// archives by theirs along with that of their directory. Errors are propagated with added context.
+fn newest_mtime(input: &Input) -> Result<SystemTime> {
+    let paths = input.listed().or(input.merged()).unwrap_or_default();
+    newest_mtime_of(input.root(), paths, !input.is_archived())
+}
+
+/// Returns the newest modification time of the directory or archive at `path`, without opening it as an `Input`.
+///
+/// A directory counts along with everything inside it, as described in `newest_mtime_of`, unless `fast` is true in
+/// which case only the modification time of `path` itself is returned. That is much faster for large directories, but
+/// only changes when entries directly inside the directory are added, removed or renamed. An archive is represented by
+/// its own modification time. Errors are propagated with added context.
+pub fn last_modified(path: &Path, fast: bool) -> Result<SystemTime> {
+    newest_mtime_of(path, &[], !fast)
+}
+
+/// Checks whether the source read from `input` has changed since `existing` was written.
+///
+/// The source has changed if anything in it is newer than `existing`, as checked by `newest_mtime`. Errors are
+/// propagated with added context.
+fn source_changed(input: &Input, existing: &Path) -> Result<bool> {
+    let written = fs::metadata(existing)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("Failed to read modification time of {}", existing.display()))?;
+
+    Ok(newest_mtime(input)? > written)
+}
+
+/// Returns the chapters of the pages written from `imgs`, which produce `page_counts` pages each.
+///
+/// Every subdirectory of the directory or archive of `input` which images are taken from is a chapter, named by its
+/// path relative to `input` and given by its first page and number of pages. Merged archives are subdirectories like
+/// any other, named without their extension. Following images from the same subdirectory continue its chapter. Images
+/// directly inside `input` don't belong to any chapter, and neither does the `cover` moved to the front.
+fn chapters(
+    input: &Input,
+    imgs: &[ImageInfo],
+    page_counts: &[usize],
+    options: &CbzOptions,
+) -> Vec<(usize, usize, String)> {
+    let mut chapters = Vec::new();
+    let mut page = 0;
+    let mut previous = None;
+    for (i, (img, &pages)) in imgs.iter().zip(page_counts).enumerate() {
+        let dir = img
+            .path
+            .parent()
+            .and_then(|dir| dir.strip_prefix(input.root()).ok())
+            .filter(|dir| !dir.as_os_str().is_empty());
+        if i == 0 && (options.cover.is_some() || options.cover_file.is_some()) {
+            page += pages;
+            continue;
+        }
+        if dir != previous {
+            if let Some(dir) = dir {
+                let merged = input.merged().is_some();
+                let name = dir
+                    .components()
+                    .enumerate()
+                    .map(|(i, component)| {
+                        let component = Path::new(component.as_os_str());
+                        if merged && i == 0 {
+                            component.file_stem().unwrap_or_default().to_string_lossy()
+                        } else {
+                            component.as_os_str().to_string_lossy()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("/");
+                chapters.push((page, 0, name));
+            }
+            previous = dir;
+        }
+        // The last chapter is the one of `dir`, as a new one has just been started otherwise.
+        if dir.is_some()
+            && let Some((_, count, _)) = chapters.last_mut()
+        {
+            *count += pages;
+        }
+        page += pages;
+    }
+    chapters
+}
+
+/// Returns the table of contents listing `chapters`, as returned by `chapters`.
+///
+/// Every chapter is listed on its own line as the range of its pages, counting from 1, followed by a tab and its name,
+/// e.g. `1-24\tChapter 1`. Pages are given by their position rather than their entry names, so the ranges stay valid
+/// whatever the pages are named.
+fn toc(chapters: &[(usize, usize, String)]) -> String {
+    let mut toc = String::new();
+    for (page, count, name) in chapters {
+        // Writing to a String never fails.
+        let _ = writeln!(toc, "{}-{}\t{name}", page + 1, page + count);
+    }
+    toc
+}
+
+/// Reports the pages each of the `merged` archives contributes, out of the pages written from `imgs`.
+///
+/// Every archive is listed with the range from the first to the last page taken from it, counting from 1, in page
+/// order. Archives without images are listed last.
+fn report_merged(merged: &[PathBuf], imgs: &[ImageInfo], page_counts: &[usize], out: &mut Output) {
+    let mut ranges = merged
+        .iter()
+        .map(|archive| {
+            let mut page = 0;
+            let mut range = None;
+            for (img, &pages) in imgs.iter().zip(page_counts) {
+                if img.path.starts_with(archive) && pages > 0 {
+                    range = Some((range.map_or(page + 1, |(first, _)| first), page + pages));
+                }
+                page += pages;
+            }
+            (range, archive)
+        })
+        .collect::<Vec<_>>();
+    ranges.sort_by(|(a, _), (b, _)| missing_last(a.as_ref(), b.as_ref()));
+    out.info(format!("Merging {} archives", merged.len()));
+    for (range, archive) in ranges {
+        match range {
+            Some((first, last)) => {
+                out.info(format!("\t{}: pages {first}-{last}", archive.display()));
+            }
+            None => out.info(format!("\t{}: no images", archive.display())),
+        }
+    }
+}
+
+/// Builds ComicInfo metadata from the command line arguments.
+///
+/// If `parse_folder_name` is true missing fields are filled in from the name of the directory or archive of `input`,
+/// without the extension of an archive or list file, printing the parsed fields if `out` is verbose. The first pages of
+/// chapters are marked using `bookmarks`. Returns None if no metadata was provided and there are no bookmarks.
+fn comic_info(
+    input: &Input,
+    options: &CbzOptions,
+    page_count: usize,
+    bookmarks: Vec<(usize, String)>,
+    out: &mut Output,
+) -> Option<ComicInfo> {
+    let parsed = if options.parse_folder_name {
+        let dir = input.root();
+        let name = if matches!(input, Input::Dir(_)) {
+            dir.file_name()
+        } else {
+            dir.file_stem()
+        };
+        let name = name.unwrap_or_default().to_string_lossy();
+        let parsed = ComicInfo::from_folder_name(&name);
+        out.verbose(format!("Parsed folder name {name}:"));
+        let fields = [
+            ("Series", parsed.series.clone()),
+            ("Number", parsed.number.clone()),
+            ("Volume", parsed.volume.map(|volume| volume.to_string())),
+            ("Year", parsed.year.map(|year| year.to_string())),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                out.verbose(format!("\t{field}: {value}"));
+            }
+        }
+        parsed
+    } else {
+        ComicInfo::default()
+    };
+    let comic_info = ComicInfo {
+        title: options.title.clone(),
+        series: options.series.clone().or(parsed.series),
+        number: options.number.clone().or(parsed.number),
+        volume: options.volume.or(parsed.volume),
+        year: options.year.or(parsed.year),
+        writer: options.writer.clone(),
+        page_count: Some(page_count),
+        cover: (options.cover.is_some() || options.cover_file.is_some()).then_some(0),
+        bookmarks,
+    };
+
+    (!comic_info.is_empty() || !comic_info.bookmarks.is_empty()).then_some(comic_info)
+}
+
+/// Zip file options of the entries of an archive, as set by `compression`, `level`, `no_smart_compression` and
+/// `config`.
+struct EntryOptions<'a> {
+    /// Options of entries without a rule in the config.
+    default: SimpleFileOptions,
+    config: Option<&'a Config>,
+    smart_compression: bool,
+}
+
+impl<'a> EntryOptions<'a> {
+    /// Returns the options of the entries of an archive created using `options`.
+    ///
+    /// `compression` and `level` override the method and level at the top of the config, which only gives the level if
+    /// its method is used. Fails if the method is not supported or the level is out of range for the method, which
+    /// can't happen for validated options.
+    fn new(options: &'a CbzOptions) -> Result<Self> {
+        let config = options.config.as_ref();
+        let level = match options.compression {
+            None => options.level.or(config.and_then(|config| config.level)),
+            Some(_) => options.level,
+        };
+
+        Ok(Self {
+            default: options.compression_method().options(level)?,
+            config,
+            smart_compression: !options.no_smart_compression,
+        })
+    }
+
+    /// Returns the options of entries of the category of `rule`, or the default options if there is no rule.
+    fn rule(&self, rule: impl Fn(&Config) -> Option<Rule>) -> Result<SimpleFileOptions> {
+        self.config
+            .and_then(rule)
+            .map_or(Ok(self.default), Rule::options)
+    }
+
+    /// Returns the options of an entry holding a page in `format`.
+    ///
+    /// Pages without a rule in the config are compressed using the default options if `smart_compression` is false or
+    /// they are PNG, BMP or TIFF pages. Pages in any other format, such as JPEG, GIF and WebP, are already compressed,
+    /// so compressing them again wastes time and can even grow them, and they are stored instead.
+    fn page(&self, format: ImageFormat) -> Result<SimpleFileOptions> {
+        if self.config.and_then(|config| config.page(format)).is_none()
+            && self.smart_compression
+            && !matches!(
+                format,
+                ImageFormat::Png | ImageFormat::Bmp | ImageFormat::Tiff
+            )
+        {
+            return Compression::Stored.options(None);
+        }
+        self.rule(|config| config.page(format))
+    }
+
+    /// Returns the options of an excluded file at `path`, which is either ComicInfo.xml or another file.
+    fn excluded(&self, path: &Path, options: &CbzOptions) -> Result<SimpleFileOptions> {
+        if is_comic_info(path, options) {
+            self.rule(|config| config.comic_info)
+        } else {
+            self.rule(|config| config.other)
+        }
+    }
+
+    /// Checks whether any entry can be compressed.
+    fn compresses(&self) -> bool {
+        is_compressed(self.default) || self.config.is_some()
+    }
+}
+
+/// Adds the file at `path` inside `input` to `archive` as-is, named `name`.
+///
+/// Files inside a directory are streamed into the archive. Zip entries are written using `zip_options`. Returns the
+/// size of the file. Errors are propagated.
+fn add_as_is(
+    archive: &mut Archive,
+    input: &Input,
+    name: &str,
+    path: &Path,
+    zip_options: SimpleFileOptions,
+    mtime: Option<SystemTime>,
+) -> Result<u64> {
+    if input.is_archived() {
+        let buf = input.read(path)?;
+        archive.add(name, &buf, zip_options, mtime)?;
+        Ok(buf.len() as u64)
+    } else {
+        archive.add_file(name, path, zip_options, mtime)
+    }
+}
+
+/// Returns the archive entry name for an excluded file.
+///
+/// Excluded files keep their file name, except for ComicInfo.xml which is named exactly `comic_info::FILE_NAME` as
+/// matched by `is_comic_info`. Anything in the file name which is not valid UTF-8 is replaced by U+FFFD.
+fn excluded_name<'a>(path: &'a Path, options: &CbzOptions) -> Cow<'a, str> {
+    if is_comic_info(path, options) {
+        Cow::Borrowed(comic_info::FILE_NAME)
+    } else {
+        path.file_name().unwrap_or_default().to_string_lossy()
+    }
+}
+
+/// Adds the excluded file at `path` inside `input` to `archive` as-is, named by `excluded_name`.
+///
+/// Zip entries are written using the options of excluded files in `entry_options`. Returns the size of the file. Errors
+/// are propagated.
+fn add_excluded(
+    archive: &mut Archive,
+    input: &Input,
+    path: &Path,
+    entry_options: &EntryOptions,
+    options: &CbzOptions,
+    out: &mut Output,
+) -> Result<u64> {
+    let mtime = source_mtime(input, path, options, out)?;
+    add_as_is(
+        archive,
+        input,
+        &excluded_name(path, options),
+        path,
+        entry_options.excluded(path, options)?,
+        mtime,
+    )
+}
+
+/// Keeps only the first of the excluded files with the same name among `excluded` when merging archives.
+///
+/// Every merged archive usually has its own ComicInfo.xml, but the merged archive can only hold one, so the one of the
+/// first archive is kept. Files are compared by their entry names as given by `excluded_name`. The others are left out,
+/// which is reported if `out` is verbose.
+fn merge_excluded(excluded: &mut Vec<PathBuf>, options: &CbzOptions, out: &mut Output) {
+    let mut names = HashSet::new();
+    excluded.retain(|path| {
+        let first = names.insert(excluded_name(path, options).to_string());
+        if !first {
+            out.verbose(format!(
+                "\t{}: left out (already added from an earlier archive)",
+                path.display()
+            ));
+        }
+        first
+    });
+}
+
+/// Keeps only one of several ComicInfo.xml files differing in the case of their names among `excluded`.
+///
+/// The file named exactly `comic_info::FILE_NAME` is kept if there is one, otherwise the first by name, so that the
+/// choice doesn't depend on the order files are found in. The others are left out with a warning. Returns whether any
+/// file was left out.
+fn pick_comic_info(excluded: &mut Vec<PathBuf>, options: &CbzOptions, out: &mut Output) -> bool {
+    let mut variants = excluded
+        .iter()
+        .filter(|path| is_comic_info(path, options))
+        .cloned()
+        .collect::<Vec<_>>();
+    if variants.len() < 2 {
+        return false;
+    }
+
+    variants.sort_by_key(|path| {
+        let name = path.file_name().unwrap_or_default().to_os_string();
+        (name != comic_info::FILE_NAME, name)
+    });
+    let kept = variants.remove(0);
+    excluded.retain(|path| !variants.contains(path));
+    out.warn(format!(
+        "Found {} ComicInfo.xml files differing in case, keeping {} and leaving out",
+        variants.len() + 1,
+        kept.display()
+    ));
+    for path in variants {
+        out.info(format!("\t{}", path.display()));
+    }
+    true
+}
+
+/// Checks that the finished archive, consisting of `parts`, can be read back.
+///
+/// Every entry is read, which also checks its checksum in a cbz, and entries named like images are decoded. Fails
+/// listing all entries which can't be read or decoded. Errors are propagated with added context.
+fn verify_output(parts: &[PathBuf], options: &CbzOptions, out: &mut Output) -> Result<()> {
+    out.info("Verifying output ...");
+    let mut count = 0;
+    let mut failed = Vec::new();
+    for part in parts {
+        count += archive::read_entries(part, options.format, |name, data| {
+            let decoded = data.map_err(anyhow::Error::from).and_then(|data| {
+                if ImageFormat::from_path(name).is_ok() {
+                    ImageReader::new(io::Cursor::new(data))
+                        .with_guessed_format()?
+                        .decode()?;
+                }
+                Ok(())
+            });
+            if let Err(e) = decoded {
+                failed.push(format!("{name} ({e})"));
+            }
+        })?;
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{} of {count} entries failed verification: {}",
+            failed.len(),
+            failed.join(", ")
+        );
+    }
+    out.info(format!("Verified {count} entries"));
+
+    Ok(())
+}
+
+/// Returns the path of the output file for `dir`.
+///
+/// This is `output` if provided. Otherwise the output file is named after `dir` with `extension` or else the extension
+/// of `format`, which replaces the extension of an archive, and is placed in `out_dir` if provided or else next to
+/// `dir`. If `sample` is provided `.sample` is inserted before the extension, e.g. `name.sample.cbz`. Fails if no file
+/// name can be derived from `dir`.
+fn output_path(dir: &Path, options: &CbzOptions) -> Result<PathBuf> {
+    let path = if let Some(output) = &options.output {
+        output.clone()
+    } else {
+        let path = match &options.out_dir {
+            Some(out_dir) => out_dir.join(dir.file_name().with_context(|| {
+                format!("Failed to derive output file name from {}", dir.display())
+            })?),
+            None => dir.to_path_buf(),
+        };
+        let extension = options
+            .extension
+            .as_deref()
+            .unwrap_or(options.format.extension());
+        if Input::is_archive(dir) {
+            path.with_extension(extension)
+        } else {
+            path.with_added_extension(extension)
+        }
+    };
+
+    Ok(if options.sample.is_some() {
+        with_suffix(&path, "sample")
+    } else {
+        path
+    })
+}
+
+/// Inserts `suffix` before the extension of `path`, e.g. `name.suffix.cbz` for `name.cbz`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    match path.extension() {
+        Some(extension) => path.with_extension(format!("{suffix}.{}", extension.to_string_lossy())),
+        None => path.with_added_extension(suffix),
+    }
+}
+
+/// Returns the path of part `n` of the archive at `path`, e.g. `name.part1.cbz` for `name.cbz`.
+fn part_path(path: &Path, n: usize) -> PathBuf {
+    with_suffix(path, &format!("part{n}"))
+}
+
+/// Archive being split into parts, so that none exceeds `max_archive_size`.
+struct Split<'a> {
+    /// Path of the whole archive, which the parts are named after.
+    path: &'a Path,
+    max_size: u64,
+    /// Paths of the parts finished so far.
+    finished: Vec<PathBuf>,
+    /// Estimated size of the current part before any entries are added which could be moved to the next part.
+    base_size: u64,
+}
+
+impl<'a> Split<'a> {
+    /// Starts splitting `archive`, being written to `path`, into parts of at most `max_size` bytes.
+    fn new(archive: &Archive, path: &'a Path, max_size: u64) -> Self {
+        Self {
+            path,
+            max_size,
+            finished: Vec::new(),
+            base_size: archive.estimated_size(),
+        }
+    }
+
+    /// Starts a new part if adding an entry named `name` with `size` bytes would make the current part of `archive`
+    /// exceed the maximum size.
+    ///
+    /// The current part is finished and moved to its numbered path, including the first part which is only named as a
+    /// part once a second one is needed. Entries are never split, so a part holds at least one entry even if that
+    /// alone exceeds the maximum size. Errors are propagated.
+    fn reserve(
+        &mut self,
+        archive: &mut Archive,
+        name: &str,
+        size: u64,
+        compress: bool,
+    ) -> Result<()> {
+        if archive.estimated_size() == self.base_size
+            || archive.estimated_size() + Archive::entry_size(name, size, compress) <= self.max_size
+        {
+            return Ok(());
+        }
+
+        let path = part_path(self.path, self.finished.len() + 1);
+        let next = Archive::create(
+            part_path(self.path, self.finished.len() + 2),
+            ArchiveFormat::Cbz,
+        )?;
+        let mut part = std::mem::replace(archive, next);
+        part.set_path(path.clone());
+        part.finish()?;
+        self.finished.push(path);
+        self.base_size = archive.estimated_size();
+
+        Ok(())
+    }
+
+    /// Finishes the last part, moving it to its numbered path if there are several parts.
+    ///
+    /// Returns the paths of all parts in order. Errors are propagated.
+    fn finish(mut self, mut archive: Archive) -> Result<Vec<PathBuf>> {
+        let path = if self.finished.is_empty() {
+            self.path.to_path_buf()
+        } else {
+            part_path(self.path, self.finished.len() + 1)
+        };
+        archive.set_path(path.clone());
+        archive.finish()?;
+        self.finished.push(path);
+
+        Ok(self.finished)
+    }
+}
+
+/// Ways of dealing with non-images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NonImageAction {
+    /// Leave them out of the archive.
+    Skip,
+    /// Don't create the archive.
+    Abort,
+    /// Add them to the archive as-is.
+    Include,
+}
+
+/// Asks the user what to do about the non-images found.
+///
+/// The user is only asked if `out` is interactive and `dry_run` is false, otherwise `NonImageAction::Abort` is
+/// returned, which is also the default answer. Errors are propagated.
+fn ask_non_images(options: &CbzOptions, out: &mut Output) -> Result<NonImageAction> {
+    if options.dry_run || !out.is_interactive() {
+        return Ok(NonImageAction::Abort);
+    }
+
+    let choice = out.prompt("[s]kip them, [a]bort or [i]nclude them anyway? [s/A/i]")?;
+    Ok(match choice.as_str() {
+        "s" | "skip" => NonImageAction::Skip,
+        "i" | "include" => NonImageAction::Include,
+        _ => NonImageAction::Abort,
+    })
+}
+
+/// Asks the user to confirm deleting `dir` once `count` pages have been written to `archive_path`.
+///
+/// Deleting is confirmed right away if `yes` or `overwrite` is true. Otherwise the user is asked if `out` is
+/// interactive, and deleting is declined if not. Not deleting is the default answer. Errors are propagated.
+fn confirm_delete(
+    dir: &Path,
+    archive_path: &Path,
+    count: usize,
+    options: &CbzOptions,
+    out: &mut Output,
+) -> Result<bool> {
+    if options.yes || options.overwrite {
+        return Ok(true);
+    }
+    if !out.is_interactive() {
+        return Ok(false);
+    }
+
+    out.info(format!(
+        "Wrote {count} pages to {}, {} will be removed",
+        archive_path.display(),
+        dir.display()
+    ));
+    let choice = out.prompt(format!("Delete {}? [y/N]", dir.display()))?;
+    Ok(choice == "y" || choice == "yes")
+}
+
+/// Moves `path` to the trash of the operating system.
+///
+/// Errors are propagated with added context.
+#[cfg(feature = "trash")]
+fn move_to_trash(path: &Path) -> Result<()> {
+    trash::delete(path).with_context(|| format!("Failed to move {} to the trash", path.display()))
+}
+
+/// Fails, as support for the trash has not been compiled in.
+#[cfg(not(feature = "trash"))]
+fn move_to_trash(path: &Path) -> Result<()> {
+    anyhow::bail!(
+        "Moving {} to the trash is not supported by this build",
+        path.display()
+    )
+}
+
+/// Creates a cbz file, or an archive in another `format`, with the images read from `input`.
+///
+/// The directory, archive, merged archives or list file of `input` is checked using `check_dir`, and its images are
+/// transformed using `transform_image` and written as numbered pages, along with any generated metadata. The options
+/// only shape how this is done, as described for each of them. The outcome is recorded in `summary`. Nothing is created
+/// if non-images are found and not skipped or included, if there are no images or if kept file names collide. Errors
+/// are propagated.
+pub fn create_cbz(
+    input: &Input,
+    options: &CbzOptions,
+    out: &mut Output,
+    summary: &mut Summary,
+) -> Result<()> {
+    // Check if output file already exists.
+    let dir = input.root();
+    let entry_options = EntryOptions::new(options)?;
+    let archive_path = output_path(dir, options)?;
+    summary.output = Some(archive_path.display().to_string());
+    let appending = options.append && archive_path.exists();
+    // Splitting writes the first part next to the archive, so an existing first part is overwritten as well.
+    let existing = Some(archive_path.clone())
+        .into_iter()
+        .chain(
+            options
+                .max_archive_size
+                .map(|_| part_path(&archive_path, 1)),
+        )
+        .find(|path| path.exists());
+    if options.overwrite_newer
+        && let Some(existing) = &existing
+        && !source_changed(input, existing)?
+    {
+        out.info(format!("{} is up to date, skipping", existing.display()));
+        return Ok(());
+    }
+    if !options.overwrite
+        && !options.overwrite_newer
+        && !options.dry_run
+        && !appending
+        && let Some(existing) = existing
+    {
+        let choice = out.prompt(format!(
+            "{} already exists. Overwrite? [y/N]",
+            existing.display()
+        ))?;
+
+        if choice != "y" && choice != "yes" {
+            out.info(format!("Not creating {}", options.format));
+            return Ok(());
+        }
+    }
+
+    // Check directory for images, non images and excluded files.
+    let Scan {
+        images: mut imgs,
+        non_images: non_imgs,
+        mut excluded,
+    } = check_dir(input, options, out)?;
+    summary.non_images = non_imgs
+        .iter()
+        .map(|(path, _)| path.display().to_string())
+        .collect();
+
+    let mut skipped_non_imgs = false;
+    if !non_imgs.is_empty() {
+        if options.skip_non_images {
+            out.warn(format!(
+                "Skipping {} non-images/unsupported images",
+                non_imgs.len()
+            ));
+        } else {
+            out.info(format!(
+                "Found {} non-images/unsupported images",
+                non_imgs.len()
+            ));
+        }
+        for (path, reason) in &non_imgs {
+            out.info(format!("\t{} ({reason})", path.display()));
+        }
+        let action = if options.skip_non_images {
+            NonImageAction::Skip
+        } else {
+            ask_non_images(options, out)?
+        };
+        match action {
+            NonImageAction::Abort => return Ok(()),
+            NonImageAction::Skip => skipped_non_imgs = true,
+            NonImageAction::Include => {
+                let (files, others): (Vec<_>, Vec<_>) = non_imgs
+                    .iter()
+                    .partition(|&&(ref path, reason)| reason != UNREADABLE && input.is_file(path));
+                if !others.is_empty() {
+                    out.warn(format!(
+                        "Leaving out {} non-images which are not readable files",
+                        others.len()
+                    ));
+                    skipped_non_imgs = true;
+                }
+                excluded.extend(files.into_iter().map(|(path, _)| path.clone()));
+            }
+        }
+    }
+    if input.merged().is_some() {
+        merge_excluded(&mut excluded, options, out);
+    }
+    // Left out files would be lost by deleting the directory, just like skipped non-images.
+    skipped_non_imgs |= pick_comic_info(&mut excluded, options, out);
+    if imgs.is_empty() {
+        out.warn(format!("No images found in {}, skipping", dir.display()));
+        return Ok(());
+    }
+    move_cover(&mut imgs, dir, options)?;
+    if let Some(cover_file) = &options.cover_file {
+        match check_file(input, cover_file, options)? {
+            Entry::Image(img) => imgs.insert(0, img),
+            Entry::Unreadable(_, err) => return Err(err),
+            Entry::NonImage(_, reason) => anyhow::bail!(
+                "Cover file {} is not a supported image ({reason})",
+                cover_file.display()
+            ),
+            Entry::Excluded(_) | Entry::Skipped(_) | Entry::Ignored(_) => {
+                unreachable!("Files are only checked for being images")
+            }
+        }
+    }
+    if let Some(sample) = options.sample
+        && imgs.len() > sample.get()
+    {
+        out.info(format!(
+            "Sampling the first {sample} of {} images",
+            imgs.len()
+        ));
+        imgs.truncate(sample.get());
+    }
+    // Pages already in the archive being appended to are kept as they are, and new pages are numbered after them.
+    let existing = if appending {
+        if fs::canonicalize(&archive_path).ok() == fs::canonicalize(dir).ok() {
+            anyhow::bail!("Can't append {} to itself", dir.display());
+        }
+        archive::entry_names(&archive_path)?
+    } else {
+        Vec::new()
+    };
+    let offset = existing
+        .iter()
+        .filter(|name| ImageFormat::from_path(name).is_ok())
+        .count();
+
+    if options.convert.is_none() {
+        let unreadable = imgs
+            .iter()
+            .filter(|img| UNREADABLE_FORMATS.contains(&img.format))
+            .count();
+        if unreadable > 0 {
+            anomaly(
+                format!(
+                    "Adding {unreadable} TIFF/BMP images without conversion, which most readers can't display. Use --convert to transcode them"
+                ),
+                options,
+                out,
+            )?;
+        }
+    }
+    if options.no_rename && !options.fix_extensions {
+        let mismatched = imgs
+            .iter()
+            .filter(|img| {
+                options
+                    .convert
+                    .is_none_or(|convert| convert.format() == img.format)
+                    && !has_extension(
+                        Path::new(img.path.file_name().unwrap_or_default()),
+                        img.format,
+                    )
+            })
+            .count();
+        if mismatched > 0 {
+            anomaly(
+                format!(
+                    "Keeping {mismatched} file names whose extension doesn't match the image format. Use --fix-extensions to correct them"
+                ),
+                options,
+                out,
+            )?;
+        }
+    }
+
+    // Replace any existing ComicInfo.xml with a generated one.
+    let page_counts = imgs
+        .iter()
+        .enumerate()
+        .map(|(i, img)| page_count(input, img, i == 0, options))
+        .collect::<Result<Vec<_>>>()?;
+    let count: usize = page_counts.iter().sum();
+    if let Some(merged) = input.merged() {
+        report_merged(merged, &imgs, &page_counts, out);
+    }
+    // The cover file is numbered before the first page of the directory, so that the other pages keep their numbers.
+    let first = options.start_number - usize::from(options.cover_file.is_some());
+    check_pad_width(first, offset + count, options)?;
+    let chapters = chapters(input, &imgs, &page_counts, options);
+    let toc = match options.toc {
+        true if chapters.is_empty() => {
+            out.warn(format!(
+                "Not generating {TOC_FILE}, as no images are taken from subdirectories"
+            ));
+            None
+        }
+        true => Some(toc(&chapters)),
+        false => None,
+    };
+    let bookmarks = if options.include_subdir_as_chapter {
+        chapters
+            .into_iter()
+            .map(|(page, _, name)| (page, name))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let comic_info = comic_info(input, options, count, bookmarks, out);
+    let series = comic_info
+        .as_ref()
+        .and_then(|comic_info| comic_info.series.clone());
+    check_series(series.as_deref(), dir, options)?;
+    // PDF documents only hold pages, so neither metadata nor other files can be added.
+    let comic_info = if matches!(options.format, ArchiveFormat::Pdf) {
+        if comic_info.is_some() {
+            out.warn(format!(
+                "Not generating {}, as a pdf can't hold it",
+                comic_info::FILE_NAME
+            ));
+        }
+        if !excluded.is_empty() {
+            out.warn(format!(
+                "Leaving out {} excluded files, as a pdf can only hold images",
+                excluded.len()
+            ));
+            excluded.clear();
+            skipped_non_imgs = true;
+        }
+        None
+    } else if appending {
+        // The existing metadata is kept with an updated page count, as the generated one would only describe the new
+        // pages.
+        if comic_info.is_some() {
+            out.warn(format!(
+                "Not generating {} when appending to {}",
+                comic_info::FILE_NAME,
+                archive_path.display()
+            ));
+        }
+        let len = excluded.len();
+        excluded.retain(|path| {
+            !existing
+                .iter()
+                .any(|name| excluded_name(path, options) == *name)
+        });
+        if excluded.len() != len {
+            out.warn(format!(
+                "Leaving out {} excluded files already in {}",
+                len - excluded.len(),
+                archive_path.display()
+            ));
+        }
+        None
+    } else {
+        comic_info
+    };
+    let appended_info = match existing
+        .iter()
+        .find(|name| !name.contains('/') && is_comic_info(Path::new(name), options))
+    {
+        Some(name) => match String::from_utf8(archive::read_entry(&archive_path, name)?) {
+            Ok(xml) => Some((
+                name.clone(),
+                comic_info::update_page_count(&xml, offset, offset + count),
+            )),
+            Err(_) => {
+                out.warn(format!(
+                    "Not updating the page count of {name} in {}, as it is not valid UTF-8",
+                    archive_path.display()
+                ));
+                None
+            }
+        },
+        _ => None,
+    };
+    if comic_info.is_some() {
+        let len = excluded.len();
+        excluded.retain(|path| !is_comic_info(path, options));
+        if excluded.len() != len {
+            out.warn(format!(
+                "Replacing existing {} with generated one",
+                comic_info::FILE_NAME
+            ));
+        }
+    }
+
+    summary.pages = count;
+    summary.excluded = excluded
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+
+    let mut naming = Naming {
+        first,
+        count,
+        series: series.as_deref(),
+        taken: existing.iter().map(|name| name.to_lowercase()).collect(),
+        fixed: 0,
+        offset,
+    };
+    check_collisions(&imgs, &page_counts, options, &naming)?;
+    if options.dry_run {
+        summary.status = Status::DryRun;
+        print_plan(
+            &archive_path,
+            &imgs,
+            &page_counts,
+            &excluded,
+            comic_info.as_ref(),
+            toc.as_deref(),
+            naming,
+            options,
+            out,
+        );
+        return Ok(());
+    }
+
+    // Create archive.
+    match options.format {
+        ArchiveFormat::Cbz if appending => out.info(format!(
+            "Appending to {} after its {offset} pages ...",
+            archive_path.display()
+        )),
+        ArchiveFormat::Cbz => out.info(format!(
+            "Creating cbz ({}) ...",
+            options.compression_method()
+        )),
+        ArchiveFormat::Cbt => out.info("Creating cbt ..."),
+        ArchiveFormat::Pdf => out.info("Creating pdf ..."),
+    }
+    if let Some(parent) = archive_path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    // The input archive is already in memory, so it can safely be overwritten.
+    let in_place = input.is_archived()
+        && archive_path.exists()
+        && fs::canonicalize(&archive_path).ok() == fs::canonicalize(dir).ok();
+    // The existing pages of an archive being appended to are counted as input, as they are part of the output.
+    let (mut archive, size_before) = if appending {
+        let size_before = fs::metadata(&archive_path)
+            .with_context(|| format!("Failed to read metadata of {}", archive_path.display()))?
+            .len();
+        let replaced = appended_info
+            .as_ref()
+            .map(|(name, xml)| (name.as_str(), xml.as_bytes()));
+        (Archive::append(&archive_path, replaced)?, size_before)
+    } else {
+        (Archive::create(&archive_path, options.format)?, 0)
+    };
+    if options.manifest {
+        archive.record_digests();
+    }
+    if let Some(comic_info) = comic_info {
+        let mtime = options.preserve_mtime.then(SystemTime::now);
+        archive.add(
+            comic_info::FILE_NAME,
+            comic_info.to_xml().as_bytes(),
+            entry_options.rule(|config| config.comic_info)?,
+            mtime,
+        )?;
+    }
+    if let Some(toc) = &toc {
+        let mtime = options.preserve_mtime.then(SystemTime::now);
+        archive.add(
+            TOC_FILE,
+            toc.as_bytes(),
+            entry_options.rule(|config| config.toc)?,
+            mtime,
+        )?;
+    }
+    // An existing ComicInfo.xml is only kept if none is generated, so at most one of them is written first.
+    if options.comic_info_first {
+        let (comic_info, others) = excluded
+            .into_iter()
+            .partition::<Vec<_>, _>(|path| is_comic_info(path, options));
+        excluded = others;
+        for path in comic_info {
+            summary.bytes_in +=
+                add_excluded(&mut archive, input, &path, &entry_options, options, out)?;
+        }
+    }
+    // The metadata describes the whole comic, so it is only written to the first part.
+    let mut split = options
+        .max_archive_size
+        .map(|max_size| Split::new(&archive, &archive_path, max_size));
+    // Include the rate if images are re-encoded, as that is what makes writing slow.
+    let bar = ProgressBar::new(
+        count
+            .try_into()
+            .context("Failed to set progress bar length")?,
+    );
+    bar.set_style(
+        ProgressStyle::with_template(if transforms_images(options) {
+            "Writing pages {bar:40} {pos}/{len} ({per_sec})"
+        } else {
+            "Writing pages {bar:40} {pos}/{len}"
+        })
+        .context("Failed to set progress bar style")?,
+    );
+    let bar = out.add_progress(bar);
+    let mtimes = imgs
+        .iter()
+        .map(|img| source_mtime(input, &img.path, options, out))
+        .collect::<Result<Vec<_>>>()?;
+    // Images are streamed into the archive if they are never transformed, to avoid holding them in memory.
+    let stream = !transforms_images(options);
+    // Images are transformed in parallel, roughly in page order so that few of them wait to be written at a time, while
+    // a single writer adds them to the archive in page order.
+    let (sender, receiver) = mpsc::sync_channel::<(usize, Result<Option<(Transformed, u64)>>)>(
+        rayon::current_num_threads(),
+    );
+    let (bytes_in, stripped, compressed, skipped) = thread::scope(|scope| {
+        let writer = scope.spawn(|| -> Result<(u64, usize, usize, Vec<SkippedImage>)> {
+            let (mut bytes_in, mut stripped, mut compressed) = (0, 0, 0);
+            let mut skipped = Vec::new();
+            let mut idx = 0;
+            let mut pending = HashMap::new();
+            let mut received = receiver.into_iter();
+            for (i, img) in imgs.iter().enumerate() {
+                let prepared = loop {
+                    if let Some(prepared) = pending.remove(&i) {
+                        break prepared;
+                    }
+                    let (i, prepared) = received
+                        .next()
+                        .context("Failed to receive transformed images")?;
+                    pending.insert(i, prepared);
+                };
+                let mtime = mtimes[i];
+                // Pages are numbered by `idx`, so the pages after a skipped image move up.
+                let prepared = match prepared {
+                    Err(e) if options.continue_on_error => {
+                        skipped.push((i, e));
+                        bar.inc(page_counts[i] as u64);
+                        continue;
+                    }
+                    prepared => prepared?,
+                };
+                let Some((transformed, len)) = prepared else {
+                    let file_name = entry_name(img, idx, None, img.format, options, &mut naming);
+                    let zip_options = entry_options.page(img.format)?;
+                    let compress = is_compressed(zip_options);
+                    if let Some(split) = &mut split {
+                        let size = input.size(&img.path)?;
+                        split.reserve(&mut archive, &file_name, size, compress)?;
+                    }
+                    bytes_in += add_as_is(
+                        &mut archive,
+                        input,
+                        &file_name,
+                        &img.path,
+                        zip_options,
+                        mtime,
+                    )?;
+                    compressed += usize::from(compress);
+                    idx += 1;
+                    bar.inc(1);
+                    continue;
+                };
+                bytes_in += len;
+                stripped += usize::from(transformed.stripped);
+                let split_image = transformed.pages.len() > 1;
+                for (part, (buf, format)) in transformed.pages.into_iter().enumerate() {
+                    let part = split_image.then_some(part);
+                    let file_name = entry_name(img, idx, part, format, options, &mut naming);
+                    let zip_options = entry_options.page(format)?;
+                    let compress = is_compressed(zip_options);
+                    if let Some(split) = &mut split {
+                        split.reserve(&mut archive, &file_name, buf.len() as u64, compress)?;
+                    }
+                    archive.add(&file_name, &buf, zip_options, mtime)?;
+                    compressed += usize::from(compress);
+                    idx += 1;
+                    bar.inc(1);
+                }
+            }
+            Ok((bytes_in, stripped, compressed, skipped))
+        });
+
+        // Sending only fails once the writer has stopped, in which case its error is returned below.
+        let _ =
+            imgs.iter()
+                .enumerate()
+                .par_bridge()
+                .try_for_each_with(sender, |sender, (i, img)| {
+                    // Images are read up front when continuing on errors, so that a failing read leaves no partial
+                    // entry.
+                    let prepared =
+                        if stream && !reencodes(img, options) && !options.continue_on_error {
+                            Ok(None)
+                        } else {
+                            input.read(&img.path).and_then(|buf| {
+                                let len = buf.len() as u64;
+                                Ok(Some((
+                                    transform_image(img, buf.into_owned(), i == 0, options)?,
+                                    len,
+                                )))
+                            })
+                        };
+                    sender.send((i, prepared))
+                });
+        writer
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })?;
+    summary.bytes_in += bytes_in;
+    out.finish_progress(&bar);
+    if !skipped.is_empty() {
+        summary.pages -= skipped.iter().map(|&(i, _)| page_counts[i]).sum::<usize>();
+        out.warn(format!(
+            "Skipped {} images which failed to be read or transformed, leaving {} pages",
+            skipped.len(),
+            summary.pages
+        ));
+        // The errors name the image, as reading and transforming errors have the path as context.
+        for (_, e) in &skipped {
+            out.info(format!("\t{e:#}"));
+            summary.errors.push(format!("{e:#}"));
+        }
+    }
+    if matches!(options.format, ArchiveFormat::Cbz) && entry_options.compresses() {
+        out.verbose(format!(
+            "Compressed {compressed} pages and stored {}",
+            summary.pages - compressed
+        ));
+    }
+    for path in excluded {
+        if let Some(split) = &mut split {
+            let file_name = excluded_name(&path, options);
+            let compress = is_compressed(entry_options.excluded(&path, options)?);
+            split.reserve(&mut archive, &file_name, input.size(&path)?, compress)?;
+        }
+        summary.bytes_in += add_excluded(&mut archive, input, &path, &entry_options, options, out)?;
+    }
+    if options.manifest {
+        // Taking the digests stops recording, so that the manifest doesn't list itself.
+        let mut manifest = String::new();
+        for (name, digest) in archive.take_digests() {
+            for byte in digest {
+                let _ = write!(manifest, "{byte:02x}");
+            }
+            let _ = writeln!(manifest, "  {name}");
+        }
+        let mtime = options.preserve_mtime.then(SystemTime::now);
+        archive.add(
+            MANIFEST_FILE,
+            manifest.as_bytes(),
+            entry_options.rule(|config| config.manifest)?,
+            mtime,
+        )?;
+    }
+    let parts = match split {
+        Some(split) => split.finish(archive)?,
+        None => {
+            archive.finish()?;
+            vec![archive_path.clone()]
+        }
+    };
+    summary.status = Status::Created;
+    summary.output = Some(parts[0].display().to_string());
+    summary.parts = parts.len();
+    let mut bytes_out = 0;
+    for part in &parts {
+        bytes_out += fs::metadata(part)
+            .with_context(|| format!("Failed to read metadata of {}", part.display()))?
+            .len();
+    }
+    summary.bytes_in += size_before;
+    summary.set_bytes_out(bytes_out);
+    if parts.len() > 1 {
+        out.info(format!("Split into {} parts", parts.len()));
+        for part in &parts {
+            out.info(format!("\t{}", part.display()));
+        }
+        // An unsplit archive of the same name, e.g. from an earlier run or the repacked input, is never removed.
+        if archive_path.exists() {
+            out.warn(format!(
+                "Leaving the unsplit {} as it is next to its parts",
+                archive_path.display()
+            ));
+        }
+    }
+    if let Some(ratio) = summary.ratio {
+        let change = (1.0 - ratio) * 100.0;
+        out.info(format!(
+            "Packed {} of input{} into {} ({:.1}% {})",
+            summary::format_size(summary.bytes_in),
+            if appending {
+                " and existing archive"
+            } else {
+                ""
+            },
+            summary::format_size(summary.bytes_out),
+            change.abs(),
+            if change >= 0.0 { "smaller" } else { "larger" }
+        ));
+    }
+    if options.strip_metadata {
+        out.info(format!("Stripped metadata from {stripped} images"));
+    }
+    if options.fix_extensions {
+        out.info(format!("Corrected {} file extensions", naming.fixed));
+    }
+    if options.verify_output {
+        verify_output(&parts, options, out)?;
+    }
+
+    // Delete directory.
+    let remove = options.delete || options.trash;
+    if remove && options.sample.is_some() {
+        out.info(format!(
+            "Not deleting {} as only a sample was packed",
+            dir.display()
+        ));
+    } else if remove && !skipped.is_empty() {
+        out.warn(format!(
+            "Not deleting {} as some of its images were skipped",
+            dir.display()
+        ));
+    } else if remove && skipped_non_imgs {
+        // Skipped files are not in the archive, so deleting them would lose data.
+        out.warn(format!(
+            "Not deleting {} as it contains skipped non-images",
+            dir.display()
+        ));
+    } else if remove && in_place && parts.len() == 1 {
+        out.info(format!(
+            "Not deleting {} as it has been replaced",
+            dir.display()
+        ));
+    } else if options.delete && !confirm_delete(dir, &archive_path, count, options, out)? {
+        out.info(format!("Not deleting {}", dir.display()));
+    } else if options.trash {
+        out.info(format!("Moving {} to the trash ...", dir.display()));
+        move_to_trash(dir)?;
+    } else if options.delete && input.is_archived() {
+        out.info("Deleting original archive ...");
+        fs::remove_file(dir).with_context(|| format!("Failed to remove file {}", dir.display()))?;
+    } else if options.delete {
+        out.info("Deleting original files and directory ...");
+        fs::remove_dir_all(dir)
+            .with_context(|| format!("Failed to remove directory {}", dir.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Returns a temporary name next to `path` which is not taken by any file.
+///
+/// The name is hidden and contains the process ID, so that it can't be confused with a page.
+fn temp_name(path: &Path) -> PathBuf {
+    (0..)
+        .map(|n| path.with_file_name(format!(".makecbz-rename-{}-{n}", process::id())))
+        .find(|temp| fs::symlink_metadata(temp).is_err())
+        .expect("Suffixes are unbounded")
+}
+
+/// Renames the images of `input` in place to the names `create_cbz` would give them, without creating an archive.
+///
+/// Images are found using `check_dir`, with non-images being left alone, and are named using `entry_name` in their own
+/// format, so `convert` and any other transformations of the pages are ignored. Every image stays in its directory.
+/// Files are first renamed to temporary names not taken by any file and only then to their new names, so that images
+/// can take over each other's names. If any rename fails all finished ones are undone. Fails before renaming anything
+/// if `input` is an archive or a new name is taken by another file. The renamed files are listed, and if `dry_run` is
+/// true they are only listed. The outcome is recorded in `summary`. Errors are propagated with added context.
+pub fn rename_files(
+    input: &Input,
+    options: &CbzOptions,
+    out: &mut Output,
+    summary: &mut Summary,
+) -> Result<()> {
+    let dir = input.root();
+    if input.is_archived() {
+        anyhow::bail!("Can't rename the entries of archive {}", dir.display());
+    }
+    let Scan {
+        images: mut imgs,
+        non_images: non_imgs,
+        ..
+    } = check_dir(input, options, out)?;
+    summary.non_images = non_imgs
+        .iter()
+        .map(|(path, _)| path.display().to_string())
+        .collect();
+    if !non_imgs.is_empty() {
+        out.info(format!("Leaving {} non-images as they are", non_imgs.len()));
+    }
+    if imgs.is_empty() {
+        out.warn(format!("No images found in {}, skipping", dir.display()));
+        return Ok(());
+    }
+    move_cover(&mut imgs, dir, options)?;
+
+    let count = imgs.len();
+    check_pad_width(options.start_number, count, options)?;
+    let series =
+        comic_info(input, options, count, Vec::new(), out).and_then(|comic_info| comic_info.series);
+    check_series(series.as_deref(), dir, options)?;
+    let mut naming = Naming {
+        first: options.start_number,
+        count,
+        series: series.as_deref(),
+        taken: HashSet::new(),
+        fixed: 0,
+        offset: 0,
+    };
+    let renames = imgs
+        .iter()
+        .enumerate()
+        .map(|(idx, img)| {
+            let name = entry_name(img, idx, None, img.format, options, &mut naming);
+            (img.path.clone(), img.path.with_file_name(name))
+        })
+        .filter(|(from, to)| from != to)
+        .collect::<Vec<_>>();
+    let sources = renames
+        .iter()
+        .map(|(from, _)| from.as_path())
+        .collect::<HashSet<_>>();
+    for (from, to) in &renames {
+        if to.exists()
+            && !sources.contains(to.as_path())
+            && fs::canonicalize(to).ok() != fs::canonicalize(from).ok()
+        {
+            anyhow::bail!(
+                "Can't rename {} to {}, as that file already exists",
+                from.display(),
+                to.display()
+            );
+        }
+    }
+    summary.pages = count;
+
+    if options.dry_run {
+        summary.status = Status::DryRun;
+        out.info(format!("Would rename {} images", renames.len()));
+    } else {
+        out.info(format!("Renaming {} images ...", renames.len()));
+    }
+    for (from, to) in &renames {
+        out.info(format!("\t{} -> {}", from.display(), to.display()));
+    }
+    if options.dry_run {
+        return Ok(());
+    }
+
+    // Every finished rename is recorded, so that all of them can be undone if a later one fails.
+    let mut done: Vec<(&Path, PathBuf)> = Vec::new();
+    let renamed = (|| -> Result<()> {
+        for (from, _) in &renames {
+            let temp = temp_name(from);
+            fs::rename(from, &temp).with_context(|| {
+                format!("Failed to rename {} to {}", from.display(), temp.display())
+            })?;
+            done.push((from, temp));
+        }
+        for ((_, to), (_, moved)) in renames.iter().zip(&mut done) {
+            fs::rename(&*moved, to).with_context(|| {
+                format!("Failed to rename {} to {}", moved.display(), to.display())
+            })?;
+            *moved = to.clone();
+        }
+        Ok(())
+    })();
+    if let Err(err) = renamed {
+        // Files are moved aside first, as the new name of one may be the old name of another.
+        let undone = (|| -> Result<()> {
+            for (_, moved) in &mut done {
+                let temp = temp_name(moved);
+                fs::rename(&*moved, &temp).with_context(|| {
+                    format!("Failed to rename {} to {}", moved.display(), temp.display())
+                })?;
+                *moved = temp;
+            }
+            for (from, moved) in &done {
+                fs::rename(moved, from).with_context(|| {
+                    format!("Failed to rename {} to {}", moved.display(), from.display())
+                })?;
+            }
+            Ok(())
+        })();
+        return Err(match undone {
+            Ok(()) => err.context("All renamed images were renamed back"),
+            Err(undo_err) => {
+                undo_err.context(format!("{err:#}, and undoing the finished renames failed"))
+            }
+        });
+    }
+    summary.status = Status::Renamed;
+
+    Ok(())
+}
+
+/// Gathers the details of the file at `path` inside `input`, which is not packed as an image for `reason` if provided.
+///
+/// The format is guessed from the contents, and the dimensions, color type and bit depth are read from the header if
+/// the format is known. The image is then fully decoded. Any error along the way is recorded as the reason unless one
+/// is provided.
+fn file_details(input: &Input, path: &Path, reason: Option<&str>) -> FileDetails {
+    let mut details = FileDetails {
+        path: path.display().to_string(),
+        format: None,
+        width: None,
+        height: None,
+        color_type: None,
+        bit_depth: None,
+        size: input.size(path).ok(),
+        decoded: false,
+        reason: reason.map(str::to_string),
+    };
+    let decoded = (|| -> Result<()> {
+        let image = ImageReader::new(input.reader(path)?)
+            .with_guessed_format()
+            .with_context(|| format!("Failed to read file {}", path.display()))?;
+        let format = image.format().context("unknown format")?;
+        details.format = Some(format!("{format:?}").to_lowercase());
+        let decoder = image.into_decoder()?;
+        let (width, height) = decoder.dimensions();
+        let color_type = decoder.color_type();
+        details.width = Some(width);
+        details.height = Some(height);
+        details.color_type = Some(format!("{color_type:?}").to_lowercase());
+        details.bit_depth =
+            Some(color_type.bits_per_pixel() / u16::from(color_type.channel_count()));
+        DynamicImage::from_decoder(decoder)?;
+        Ok(())
+    })();
+    match decoded {
+        Ok(()) => details.decoded = true,
+        Err(e) => {
+            details.reason.get_or_insert_with(|| format!("{e:#}"));
+        }
+    }
+    details
+}
+
+/// Lists the details of the files of `input`, without creating an archive.
+///
+/// Files are found using `check_dir`, and the details of the images in page order, followed by the non-images and the
+/// excluded files, are gathered in parallel as described in `file_details`. They are printed as a table of aligned
+/// columns and recorded in `summary`. Errors are propagated.
+pub fn inspect_files(
+    input: &Input,
+    options: &CbzOptions,
+    out: &mut Output,
+    summary: &mut Summary,
+) -> Result<()> {
+    let Scan {
+        images: imgs,
+        non_images: non_imgs,
+        excluded,
+    } = check_dir(input, options, out)?;
+    let files = imgs
+        .iter()
+        .map(|img| (&img.path, None))
+        .chain(non_imgs.iter().map(|(path, reason)| (path, Some(*reason))))
+        .chain(excluded.iter().map(|path| (path, Some("excluded"))))
+        .collect::<Vec<_>>();
+    summary.files = files
+        .into_par_iter()
+        .map(|(path, reason)| file_details(input, path, reason))
+        .collect();
+    summary.pages = imgs.len();
+    summary.non_images = non_imgs
+        .iter()
+        .map(|(path, _)| path.display().to_string())
+        .collect();
+    summary.excluded = excluded
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+
+    let header = [
+        "File",
+        "Format",
+        "Dimensions",
+        "Color",
+        "Depth",
+        "Bytes",
+        "Decoded",
+        "Reason",
+    ]
+    .map(str::to_string);
+    let rows = summary
+        .files
+        .iter()
+        .map(|details| {
+            let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+            [
+                details.path.clone(),
+                or_dash(details.format.clone()),
+                or_dash(
+                    details
+                        .width
+                        .zip(details.height)
+                        .map(|(width, height)| format!("{width}x{height}")),
+                ),
+                or_dash(details.color_type.clone()),
+                or_dash(details.bit_depth.map(|bit_depth| bit_depth.to_string())),
+                or_dash(details.size.map(|size| size.to_string())),
+                if details.decoded { "yes" } else { "no" }.to_string(),
+                details.reason.clone().unwrap_or_default(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let mut widths = [0; 8];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        out.info(line.trim_end());
+    }
+    summary.status = Status::Inspected;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::output::Verbosity;
+
+    /// Returns the information of a PNG image at `path`.
+    fn image(path: PathBuf) -> ImageInfo {
+        ImageInfo {
+            path,
+            format: ImageFormat::Png,
+            animated: false,
+            frames: 1,
+            cmyk: false,
+            normalize: false,
+            dimensions: (1, 1),
+            digest: None,
+            phash: None,
+            size: 0,
+            modified: None,
+            capture_time: None,
+        }
+    }
+
+    /// Returns the entry names of images at `paths`, keeping their file names.
+    fn kept_names(paths: Vec<PathBuf>) -> Vec<String> {
+        let options = CbzOptions {
+            no_rename: true,
+            ..Default::default()
+        };
+        let mut naming = Naming {
+            first: 1,
+            count: paths.len(),
+            series: None,
+            taken: HashSet::new(),
+            fixed: 0,
+            offset: 0,
+        };
+        paths
+            .into_iter()
+            .enumerate()
+            .map(|(idx, path)| {
+                entry_name(
+                    &image(path),
+                    idx,
+                    None,
+                    ImageFormat::Png,
+                    &options,
+                    &mut naming,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn entry_name_keeps_utf8_names() {
+        assert_eq!(kept_names(vec![PathBuf::from("dir/é.png")]), ["é.png"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn entry_name_keeps_lossy_names_distinct() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let paths = [b"a\xff.png", b"a\xfe.png"]
+            .map(|name| Path::new("dir").join(OsStr::from_bytes(name)))
+            .into();
+        assert_eq!(kept_names(paths), ["a\u{FFFD}.png", "a\u{FFFD}_2.png"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_dir_reports_symlink_cycles() {
+        let dir = env::temp_dir().join(format!("makecbz-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("loop")).unwrap();
+
+        let options = CbzOptions {
+            recursive: true,
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let scan = check_dir(
+            &Input::Dir(dir.clone()),
+            &options,
+            &mut Output::new(false, Verbosity::Quiet),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+
+        let scan = scan.unwrap();
+        assert!(scan.images.is_empty());
+        assert_eq!(scan.non_images, [(dir.join("loop"), "symlink cycle")]);
+    }
+}
@@ -0,0 +1,83 @@
+//! Log file of the actions taken.
+//!
+//! Every processed directory appends a single line of tab-separated fields: the time it finished in UTC, its status,
+//! the number of pages, the directory, the output path or `-`, and any error. Tabs and newlines inside fields are
+//! replaced by spaces, so that each line can be split on tabs.
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::summary::Summary;
+use anyhow::{Context, Result};
+use time::OffsetDateTime;
+
+/// Log file shared by all directories.
+pub struct Log {
+    file: Mutex<fs::File>,
+}
+
+/// Replaces characters which would break up the line or its fields.
+fn field(text: &str) -> String {
+    text.replace(['\t', '\n', '\r'], " ")
+}
+
+/// Formats a time in UTC as `YYYY-MM-DDTHH:MM:SSZ`.
+fn timestamp(time: OffsetDateTime) -> String {
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    )
+}
+
+impl Log {
+    /// Opens the log file at `path` for appending, creating it if necessary.
+    ///
+    /// Errors are propagated with added context.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends the line of a processed directory and flushes it.
+    ///
+    /// The line is written at once, so that lines of directories processed concurrently don't interleave. Errors are
+    /// propagated with added context.
+    pub fn write(&self, summary: &Summary) -> Result<()> {
+        let mut line = [
+            timestamp(OffsetDateTime::now_utc()),
+            summary.status.name().to_string(),
+            summary.pages.to_string(),
+            field(&summary.dir),
+            summary
+                .output
+                .as_deref()
+                .map_or_else(|| "-".to_string(), field),
+        ]
+        .join("\t");
+        if !summary.errors.is_empty() {
+            line.push('\t');
+            line.push_str(&field(&summary.errors.join("; ")));
+        }
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        file.write_all(line.as_bytes())
+            .and_then(|()| file.flush())
+            .context("Failed to write to log file")
+    }
+}
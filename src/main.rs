@@ -1,283 +1,644 @@
 //! Creates a cbz from provided directory(s) after checking for valid image files.
 //!
-//! Optionally deletes the original files and directories.
+//! Optionally deletes the original files and directories. This is a thin command line wrapper around the `makecbz`
+//! library, which does all the work.
 use std::{
-    cmp::max,
-    fs,
-    io::{self, Write},
-    path::{Path, PathBuf},
+    env,
+    io::{self, IsTerminal},
+    num::NonZeroUsize,
+    path::PathBuf,
     process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::Result;
+use clap::{
+    ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum, error::ErrorKind,
+    parser::ValueSource,
+};
+use clap_complete::Shell;
 use colored::Colorize;
-use image::{ImageFormat, ImageReader};
-use indicatif::{ProgressBar, ProgressStyle};
-use zip::{ZipWriter, write::SimpleFileOptions};
-
-/// Supported image formats.
-const FORMATS: [ImageFormat; 4] = [
-    ImageFormat::Jpeg,
-    ImageFormat::Png,
-    ImageFormat::Gif,
-    ImageFormat::WebP,
-];
-/// Excluded file names.
-const EXCLUDED_FILES: [&str; 1] = ["ComicInfo.xml"];
+use image::ImageFormat;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use makecbz::{
+    CbzOptions, ConvertFormat, FORMATS,
+    archive::{ArchiveFormat, Compression},
+    create_cbz,
+    input::Input,
+    inspect_files, last_modified,
+    log::Log,
+    output::{Output, Verbosity},
+    rename_files,
+    summary::{self, Status, Summary},
+};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
-/// Command line arguments.
-#[derive(Parser, Debug)]
-#[expect(
-    clippy::struct_excessive_bools,
-    reason = "Bools represent command line switches"
-)]
-#[command(version, about=None, long_about=None)]
-struct Args {
-    #[arg(required = true, help = "Directory(s) containing images")]
-    dirs: Vec<PathBuf>,
-    #[arg(short, long, help = "Don't rename files")]
-    no_rename: bool,
-    #[arg(short, long, help = "Delete original files")]
-    delete: bool,
-    #[arg(short, long, help = "Verify image data")]
-    verify: bool,
-    #[arg(long, help = "Overwrite output file if it exists")]
-    overwrite: bool,
-}
+/// Exit code used if no directory failed, but at least one was skipped due to non-images.
+///
+/// It differs from the exit code 2 of usage errors, so that invalid arguments can be told apart.
+const EXIT_NON_IMAGES: u8 = 3;
 
-/// Image information.
+/// When to color the output.
 ///
-/// Stores the path and guessed format of an image.
-struct ImageInfo {
-    path: PathBuf,
-    format: ImageFormat,
+/// `auto` colors the output if stdout is a terminal and `NO_COLOR` is not set.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
 }
 
-/// Returns a sorted list of all paths in the provided directory.
-///
-/// Propagates any error with added context.
-fn get_paths<P>(dir: P) -> Result<Vec<PathBuf>>
-where
-    P: AsRef<Path>,
-{
-    let dir = dir.as_ref();
-    let mut paths = Vec::new();
-    for entry in
-        fs::read_dir(dir).with_context(|| format!("Failed to read directory {}", dir.display()))?
-    {
-        let entry = entry.with_context(|| format!("Error while reading {}", dir.display()))?;
-        paths.push(entry.path());
+impl ColorChoice {
+    /// Checks whether the output should be colored.
+    ///
+    /// `NO_COLOR` disables color in `auto` mode if it is set to anything but an empty string.
+    fn colorize(self) -> bool {
+        match self {
+            Self::Auto => {
+                io::stdout().is_terminal()
+                    && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+            }
+            Self::Always => true,
+            Self::Never => false,
+        }
     }
-
-    paths.sort();
-    Ok(paths)
 }
 
-/// Checks if a file is a valid image.
+/// Bundle of options for a common use, applied by `--preset`.
 ///
-/// If it is a supported image, returns an `ImageInfo` with the path and guessed format, else returns None. If `verify`
-/// is true the image is decoded to ensure there is no corruption. Propagates any error with added context.
-fn check_file<P>(file: P, verify: bool) -> Result<Option<ImageInfo>>
-where
-    P: AsRef<Path>,
-{
-    let file = file.as_ref();
-    let mut image = ImageReader::open(file)
-        .with_context(|| format!("Failed to open {} for reading", file.display()))?;
-    image.clear_format(); // Clear format guessed from file name.
-    image = image
-        .with_guessed_format()
-        .with_context(|| format!("Failed to read file {}", file.display()))?;
+/// `ereader` shrinks pages to 1920 pixels in height and converts them to WebP at quality 80, `archival` keeps the
+/// images as they are while verifying them and compressing PNG, BMP and TIFF pages with deflate, and `web` shrinks
+/// pages to 1600 pixels in width and converts them to WebP at quality 75 without metadata.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Preset {
+    Ereader,
+    Archival,
+    Web,
+}
 
-    if let Some(format) = image.format() {
-        if FORMATS.contains(&format) {
-            if verify && image.decode().is_err() {
-                return Ok(None);
+impl Preset {
+    /// Applies the preset to `options`, except for options given on the command line as recorded in `matches`.
+    ///
+    /// Explicitly given options always take precedence, including alternatives the preset would conflict with, such as
+    /// `--webp-lossless` in place of `--quality`. Flags the preset turns on are left off by their negations, such as
+    /// `--no-verify`. Compression is only set for the cbz format, as the others don't support it.
+    fn apply(self, options: &mut CbzOptions, matches: &ArgMatches) {
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let convert = |options: &mut CbzOptions, quality| {
+            if !given("convert") {
+                options.convert = Some(ConvertFormat::Webp);
+            }
+            if !given("quality") && !given("webp_lossless") {
+                options.quality = quality;
+            }
+        };
+        match self {
+            Self::Ereader => {
+                if !given("max_height") {
+                    options.max_height = Some(1920);
+                }
+                convert(options, 80);
+            }
+            Self::Archival => {
+                if !given("compression") && matches!(options.format, ArchiveFormat::Cbz) {
+                    options.compression = Some(Compression::Deflate);
+                }
+                if !given("no_verify") {
+                    options.verify = true;
+                }
+            }
+            Self::Web => {
+                if !given("max_width") {
+                    options.max_width = Some(1600);
+                }
+                if !given("keep_metadata") {
+                    options.strip_metadata = true;
+                }
+                convert(options, 75);
             }
-
-            return Ok(Some(ImageInfo {
-                path: file.to_path_buf(),
-                format,
-            }));
         }
     }
+}
+
+/// Longer description shown by `--help`.
+const LONG_ABOUT: &str = "\
+Creates a cbz from directories of images after checking that every file is a supported image.
+
+Images are renamed numerically in natural order by default, and can optionally be converted, resized
+or re-encoded on the way. Existing cbz/zip archives are repacked in place. Nothing is written if
+non-images are found, unless told what to do about them.";
+
+/// Worked examples shown after the options.
+const EXAMPLES: &str = "\
+Examples:
+  Pack a directory into dir.cbz next to it:
+    makecbz dir
+
+  Convert to WebP and shrink pages taller than 2000 pixels:
+    makecbz --convert webp --quality 85 --max-height 2000 dir
+
+  Pack every chapter directory, scanning subdirectories and adding metadata:
+    makecbz --recursive --series 'Some Series' --writer 'Some Writer' chapter-*
+
+  Preview what would be done without writing anything:
+    makecbz --dry-run --delete dir";
 
-    Ok(None)
+/// Command line arguments.
+#[derive(Parser, Debug)]
+#[command(version, about = None, long_about = LONG_ABOUT, after_help = EXAMPLES)]
+struct Args {
+    #[arg(
+        required_unless_present_any = ["list_formats", "from_list", "generate_completions"],
+        help = "Directory(s) containing images, or cbz/zip archives to repack"
+    )]
+    dirs: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["dirs", "delete", "trash", "ignore_file", "merge_archives", "page_order_file"],
+        requires = "output",
+        help = "Pack the images and directories listed in a file, one path per line, in order"
+    )]
+    from_list: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        conflicts_with = "from_list",
+        help = "Only process directories modified within this long, e.g. 7d or 48h"
+    )]
+    since: Option<Duration>,
+    #[arg(
+        long,
+        requires = "since",
+        help = "Check --since by the modification times of the directories alone instead of everything inside them"
+    )]
+    fast_since: bool,
+    #[arg(long, exclusive = true, help = "List supported image formats and exit")]
+    list_formats: bool,
+    #[arg(
+        long,
+        value_name = "SHELL",
+        exclusive = true,
+        hide = true,
+        help = "Print a completion script for the shell and exit"
+    )]
+    generate_completions: Option<Shell>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Apply a bundle of options for a common use, which individual options override"
+    )]
+    preset: Option<Preset>,
+    #[arg(
+        long,
+        requires = "preset",
+        conflicts_with = "verify",
+        help = "Don't verify image data, overriding the preset"
+    )]
+    no_verify: bool,
+    #[arg(
+        long,
+        requires = "preset",
+        conflicts_with = "strip_metadata",
+        help = "Keep metadata of images, overriding the preset"
+    )]
+    keep_metadata: bool,
+    #[command(flatten)]
+    options: CbzOptions,
+    #[arg(
+        short,
+        long,
+        default_value = "1",
+        help = "Number of directories to process concurrently"
+    )]
+    jobs: NonZeroUsize,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of threads to use [default: number of logical cores]"
+    )]
+    threads: Option<NonZeroUsize>,
+    #[arg(short, long, help = "Only print errors")]
+    quiet: bool,
+    #[arg(
+        long,
+        conflicts_with = "verbose",
+        help = "Print a JSON summary instead of the usual output"
+    )]
+    json: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Append a line per processed directory to a log file"
+    )]
+    log_file: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with = "quiet",
+        help = "Print details about every file"
+    )]
+    verbose: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "auto",
+        help = "When to color warnings and errors"
+    )]
+    color: ColorChoice,
 }
 
-/// Checks a directory for images.
+/// Units of durations, from the largest to the smallest, with their lengths in seconds.
+const DURATION_UNITS: [(&str, u64); 5] = [
+    ("w", 7 * 24 * 60 * 60),
+    ("d", 24 * 60 * 60),
+    ("h", 60 * 60),
+    ("m", 60),
+    ("s", 1),
+];
+
+/// Parses a duration given as a whole number followed by a unit.
 ///
-/// Returns a tuple of supported image files, non-image files or non-supported files and excluded files. If `verify` is
-/// true all images are decoded to ensure there is no corruption. Propgates any error.
-fn check_dir<P>(dir: P, verify: bool) -> Result<(Vec<ImageInfo>, Vec<PathBuf>, Vec<PathBuf>)>
-where
-    P: AsRef<Path>,
-{
-    println!("Checking directory ...");
-    let mut imgs = Vec::new();
-    let mut non_imgs = Vec::new();
-    let mut excluded = Vec::new();
-    let paths = get_paths(dir)?;
-    let bar = ProgressBar::new(
-        paths
-            .len()
-            .try_into()
-            .context("Failed to set progress bar length")?,
-    );
-    bar.set_style(
-        ProgressStyle::with_template("Verifying files {bar:40} {pos}/{len}")
-            .context("Failed to set progress bar style")?,
+/// Units are `s`, `m`, `h`, `d` and `w` for seconds, minutes, hours, days and weeks, ignoring case.
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let (number, unit) = duration.split_at(
+        duration
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(duration.len()),
     );
-    for path in paths {
-        if !path.is_file() {
-            non_imgs.push(path);
-        } else if EXCLUDED_FILES.contains(
-            &path
-                .file_name()
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default(),
-        ) {
-            excluded.push(path);
-        } else {
-            let image_info = check_file(&path, verify)?;
-            match image_info {
-                Some(image_info) => {
-                    imgs.push(image_info);
-                }
-                None => {
-                    non_imgs.push(path);
-                }
-            }
-        }
+    let unit = unit.trim().to_ascii_lowercase();
+    let (_, seconds) = DURATION_UNITS
+        .into_iter()
+        .find(|&(name, _)| name == unit)
+        .ok_or_else(|| anyhow::anyhow!("Unknown unit {unit:?}, expected s, m, h, d or w"))?;
+    let seconds = number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(seconds))
+        .ok_or_else(|| anyhow::anyhow!("Duration must be a whole number followed by a unit"))?;
+    Ok(Duration::from_secs(seconds))
+}
 
-        if verify {
-            bar.inc(1);
-        }
-    }
-    if verify {
-        bar.finish();
-        eprintln!(); // TODO: indicatif has a bug where final newline is not printed. (https://github.com/console-rs/indicatif/issues/695)
+/// Formats a duration in the largest unit it is a whole number of, as parsed by `parse_duration`.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (name, length) = DURATION_UNITS
+        .into_iter()
+        .find(|&(_, length)| seconds.is_multiple_of(length))
+        .unwrap_or(DURATION_UNITS[DURATION_UNITS.len() - 1]);
+    format!("{}{name}", seconds / length)
+}
+
+/// Prints the image formats supported by this build.
+///
+/// Lists the supported input formats and the formats images can be converted to with `convert`.
+fn list_formats() {
+    fn names(formats: impl Iterator<Item = ImageFormat>) -> String {
+        formats
+            .map(|format| format!("{format:?}").to_lowercase())
+            .collect::<Vec<_>>()
+            .join(", ")
     }
 
-    Ok((imgs, non_imgs, excluded))
+    let input = FORMATS
+        .into_iter()
+        .filter(|format| format.reading_enabled());
+    println!("Input formats: {}", names(input));
+    let convert = ConvertFormat::value_variants()
+        .iter()
+        .filter(|convert| convert.is_supported())
+        .map(|convert| convert.format());
+    println!("Convert formats: {}", names(convert));
 }
 
-/// Creates a cbz file with images from given directory.
+/// Parse command line arguments and call `create_cbz` for each provided directory.
 ///
-/// All image files are renamed to a numeric format unless `no_rename` is true. If `delete` is true `dir` is deleted
-/// after creating the cbz. Images can be verified using `verified`. Unless `overwrite` is true if the output file
-/// exists the user is prompted for overwriting it. Errors are propagated.
-fn create_cbz<P>(dir: P, args: &Args) -> Result<()>
-where
-    P: AsRef<Path>,
-{
-    // Check if output file already exists.
-    let dir = dir.as_ref();
-    let zip_path = dir.with_added_extension("cbz");
-    if !args.overwrite && zip_path.exists() {
-        print!(
-            "{} {} already exists. Overwrite? [y/N] ",
-            "[WARNING]".yellow().bold(),
-            zip_path.display()
+/// `list_formats` and `generate_completions` print their output and exit instead. If `since` is provided directories
+/// not modified within it, as checked by `last_modified`, are skipped up front. Directories are processed `jobs` at a
+/// time, with the output of each directory printed at once when it finishes. All parallel work, including checking
+/// files, runs on at most `threads` threads. If `json` is true a summary of all directories is printed at the end
+/// instead. If `log_file` is provided a line is appended to it for every directory as described in `log`. Invalid
+/// combinations of arguments are rejected with a usage error before anything is processed. Exits with 1 if any
+/// directory failed, with `EXIT_NON_IMAGES` if any directory was skipped due to non-images and with 0 otherwise. Usage
+/// errors exit with 2 as reported by clap.
+fn main() -> ExitCode {
+    // The matches are kept to tell which options were given explicitly, so that they override the preset.
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(preset) = args.preset {
+        preset.apply(&mut args.options, &matches);
+    }
+    colored::control::set_override(args.color.colorize());
+    if args.list_formats {
+        list_formats();
+        return ExitCode::SUCCESS;
+    }
+    if let Some(shell) = args.generate_completions {
+        let mut command = Args::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+        return ExitCode::SUCCESS;
+    }
+    // Invalid combinations which clap can't express are reported just like its own usage errors.
+    if args.options.output.is_some() && args.dirs.len() > 1 {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--output can only be used with a single directory",
+            )
+            .exit();
+    }
+    if args.options.page_order_file.is_some() && args.dirs.len() > 1 {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--page-order-file can only be used with a single directory",
+            )
+            .exit();
+    }
+    let warnings = args.options.validate().unwrap_or_else(|e| {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, format!("{e:#}"))
+            .exit()
+    });
+    if args.options.delete
+        && !args.options.yes
+        && !args.options.dry_run
+        && !io::stdin().is_terminal()
+    {
+        eprintln!(
+            "{} --delete requires --yes when not run interactively",
+            "[ERROR]".red().bold()
         );
-        io::stdout().flush().context("Failed to flush stdout")?;
-        let mut choice = String::new();
-        io::stdin()
-            .read_line(&mut choice)
-            .context("Failed to read user input")?;
-        choice = choice.to_lowercase();
-        let choice = choice.trim();
-
-        if choice != "y" && choice != "yes" {
-            println!("Not creating cbz");
-            return Ok(());
-        }
+        return ExitCode::FAILURE;
+    }
+    // Only errors are printed alongside the summary, as they go to stderr.
+    let verbosity = Verbosity::from_switches(args.quiet || args.json, args.verbose);
+    for warning in warnings {
+        Output::new(false, verbosity).warn(warning);
     }
 
-    // Check directory for images, non images and excluded files.
-    let (imgs, non_imgs, excluded) = check_dir(dir, args.verify)?;
+    if let Some(threads) = args.threads
+        && let Err(e) = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.get())
+            .build_global()
+    {
+        eprintln!(
+            "{} Failed to create thread pool: {e}",
+            "[ERROR]".red().bold()
+        );
+        return ExitCode::FAILURE;
+    }
 
-    if !non_imgs.is_empty() {
-        println!("Found {} non-images/unsupported images", non_imgs.len());
-        for path in non_imgs {
-            println!("\t{}", path.display());
+    let log = match args.log_file.as_deref().map(Log::open).transpose() {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("{} {e:#}", "[ERROR]".red().bold());
+            return ExitCode::FAILURE;
         }
-        return Ok(());
-    }
+    };
 
-    // Create cbz.
-    println!("Creating cbz ...");
-    let mut zip = ZipWriter::new(
-        fs::File::create(&zip_path)
-            .with_context(|| format!("Failed to create file {}", zip_path.display()))?,
-    );
-    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
-    for (idx, img) in imgs.iter().enumerate() {
-        let buf = fs::read(&img.path)
-            .with_context(|| format!("Failed to read file {}", img.path.display()))?;
-        let file_name = if args.no_rename {
-            img.path
-                .file_name()
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default()
-                .to_string()
+    // A list file is processed in place of a directory.
+    let dirs = args
+        .from_list
+        .clone()
+        .map_or_else(|| args.dirs.clone(), |list| vec![list]);
+    // Directories which haven't been modified recently are left out before anything else, so they aren't processed,
+    // logged or summarized at all.
+    let dirs = match args.since {
+        Some(since) => {
+            let cutoff = SystemTime::now()
+                .checked_sub(since)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let mut out = Output::new(false, verbosity);
+            dirs.into_iter()
+                .filter(|dir| match last_modified(dir, args.fast_since) {
+                    Ok(modified) if modified < cutoff => {
+                        out.info(format!(
+                            "Skipping {}, as it has not been modified in the last {}",
+                            dir.display(),
+                            format_duration(since)
+                        ));
+                        false
+                    }
+                    // Directories which can't be checked are kept, so that processing them reports the error.
+                    _ => true,
+                })
+                .collect()
+        }
+        None => dirs,
+    };
+    // The overall progress is only worth showing for several directories, and only on a terminal.
+    let progress = (dirs.len() > 1 && verbosity > Verbosity::Quiet && io::stdout().is_terminal())
+        .then(MultiProgress::new);
+    let overall = match &progress {
+        Some(progress) => {
+            match ProgressStyle::with_template("Directories {bar:40} {pos}/{len} (ETA {eta})") {
+                Ok(style) => {
+                    let overall =
+                        progress.add(ProgressBar::new(dirs.len() as u64).with_style(style));
+                    overall.tick();
+                    overall
+                }
+                Err(e) => {
+                    eprintln!(
+                        "{} Failed to set progress bar style: {e}",
+                        "[ERROR]".red().bold()
+                    );
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        None => ProgressBar::hidden(),
+    };
+    let output = |buffered| {
+        let out = Output::new(buffered, verbosity);
+        match &progress {
+            Some(progress) => out.with_progress(progress.clone()),
+            None => out,
+        }
+    };
+    let process = |i: usize, dir: &PathBuf, out: &mut Output| {
+        if progress.is_some() {
+            out.info(format!(
+                "[{}/{}] Processing {} ...",
+                i + 1,
+                dirs.len(),
+                dir.display()
+            ));
         } else {
-            format!(
-                "{:0pad$}.{}",
-                idx + 1,
-                img.format.extensions_str()[0],
-                pad = max(imgs.len().to_string().len(), 2)
-            )
+            out.info(format!("Processing {} ...", dir.display()));
+        }
+        let mut summary = Summary::new(dir);
+        let input = if args.from_list.is_some() {
+            Input::from_list(dir)
+        } else if args.options.merge_archives {
+            Input::merge(dir)
+        } else {
+            Input::open(dir)
         };
-        zip.start_file(&file_name, options)
-            .with_context(|| format!("Failed to add {} to {}", file_name, zip_path.display()))?;
-        zip.write_all(&buf)
-            .with_context(|| format!("Failed to write {} to {}", file_name, zip_path.display()))?;
+        let processed = input.and_then(|input| {
+            if args.options.rename_only {
+                rename_files(&input, &args.options, out, &mut summary)
+            } else if args.options.image_info {
+                inspect_files(&input, &args.options, out, &mut summary)
+            } else {
+                create_cbz(&input, &args.options, out, &mut summary)
+            }
+        });
+        if let Err(e) = processed {
+            out.error(format!("{e:#}"));
+            summary.status = Status::Failed;
+            summary.errors.push(format!("{e:#}"));
+        }
+        if let Some(log) = &log
+            && let Err(e) = log.write(&summary)
+        {
+            out.error(format!("{e:#}"));
+        }
+        overall.inc(1);
+        summary
+    };
+    let summaries: Vec<_> = if args.jobs.get() == 1 {
+        dirs.iter()
+            .enumerate()
+            .map(|(i, dir)| {
+                let mut out = output(false);
+                if i > 0 {
+                    out.info("");
+                }
+                process(i, dir, &mut out)
+            })
+            .collect()
+    } else {
+        // Files are checked on the same pool, so it is capped at the number of threads.
+        let pool = match rayon::ThreadPoolBuilder::new()
+            .num_threads(args.jobs.get().min(rayon::current_num_threads()))
+            .build()
+        {
+            Ok(pool) => pool,
+            Err(e) => {
+                eprintln!(
+                    "{} Failed to create thread pool: {e}",
+                    "[ERROR]".red().bold()
+                );
+                return ExitCode::FAILURE;
+            }
+        };
+        let flushed = AtomicBool::new(false);
+        pool.install(|| {
+            dirs.par_iter()
+                .enumerate()
+                .map(|(i, dir)| {
+                    let mut out = output(true);
+                    let summary = process(i, dir, &mut out);
+                    out.flush(
+                        verbosity > Verbosity::Quiet && flushed.swap(true, Ordering::Relaxed),
+                    );
+                    summary
+                })
+                .collect()
+        })
+    };
+    overall.finish_and_clear();
+    if args.json {
+        println!("{}", summary::to_json(&summaries));
     }
-    for path in excluded {
-        let buf =
-            fs::read(&path).with_context(|| format!("Failed to read file {}", path.display()))?;
-        let file_name = path
-            .file_name()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap_or_default();
-        zip.start_file(file_name, options)
-            .with_context(|| format!("Failed to add {} to {}", file_name, zip_path.display()))?;
-        zip.write_all(&buf)
-            .with_context(|| format!("Failed to write {} to {}", file_name, zip_path.display()))?;
+
+    // Errors take precedence, as they are the more severe outcome.
+    if summaries
+        .iter()
+        .any(|summary| summary.status == Status::Failed)
+    {
+        ExitCode::FAILURE
+    } else if !args.options.skip_non_images
+        && summaries
+            .iter()
+            .any(|summary| summary.status == Status::Skipped && !summary.non_images.is_empty())
+    {
+        ExitCode::from(EXIT_NON_IMAGES)
+    } else {
+        ExitCode::SUCCESS
     }
-    zip.finish()
-        .with_context(|| format!("Failed to finalize {}", zip_path.display()))?;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Delete directory.
-    if args.delete {
-        println!("Deleting original files and directory ...");
-        fs::remove_dir_all(dir)
-            .with_context(|| format!("Failed to remove directory {}", dir.display()))?;
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(["makecbz"].iter().chain(args))
     }
 
-    Ok(())
-}
+    fn assert_conflict(args: &[&str]) {
+        let Err(error) = parse(args) else {
+            panic!("{args:?} should conflict");
+        };
+        assert_eq!(error.kind(), ErrorKind::ArgumentConflict, "{args:?}");
+    }
 
-/// Parse command line arguments and call `create_cbz` for each provided directory.
-fn main() -> ExitCode {
-    let args = Args::parse();
+    #[test]
+    fn conflicting_arguments_are_rejected() {
+        assert_conflict(&["dir", "--delete", "--trash"]);
+        assert_conflict(&["dir", "--output", "a.cbz", "--out-dir", "out"]);
+        assert_conflict(&["dir", "--webp-lossless", "--quality", "90"]);
+        assert_conflict(&["dir", "--lexical-sort", "--sort-by", "natural"]);
+    }
 
-    let mut exit_code = ExitCode::SUCCESS;
-    for (i, dir) in args.dirs.iter().enumerate() {
-        if i > 0 {
-            println!();
+    #[test]
+    fn invalid_combinations_fail_validation() {
+        let invalid: [&[&str]; 3] = [
+            &["dir", "--format", "cbt", "--compression", "stored"],
+            &["dir", "--compression", "deflate", "--level", "10"],
+            &["dir", "--format", "pdf", "--manifest"],
+        ];
+        for args in invalid {
+            let parsed = parse(args).expect("Arguments should parse");
+            assert!(parsed.options.validate().is_err(), "{args:?}");
         }
-        println!("Processing {} ...", dir.display());
-        if let Err(e) = create_cbz(dir, &args) {
-            eprintln!("{} {e:#}", "[ERROR]".red().bold());
-            exit_code = ExitCode::FAILURE;
+    }
+
+    /// Parses `args` and applies the preset, if any.
+    fn parse_with_preset(args: &[&str]) -> Args {
+        let matches = Args::command()
+            .try_get_matches_from(["makecbz"].iter().chain(args))
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        if let Some(preset) = args.preset {
+            preset.apply(&mut args.options, &matches);
         }
+        args
     }
 
-    exit_code
+    #[test]
+    fn presets_respect_negated_flags() {
+        assert!(
+            parse_with_preset(&["dir", "--preset", "archival"])
+                .options
+                .verify
+        );
+        assert!(
+            !parse_with_preset(&["dir", "--preset", "archival", "--no-verify"])
+                .options
+                .verify
+        );
+        assert!(
+            parse_with_preset(&["dir", "--preset", "web"])
+                .options
+                .strip_metadata
+        );
+        assert!(
+            !parse_with_preset(&["dir", "--preset", "web", "--keep-metadata"])
+                .options
+                .strip_metadata
+        );
+        assert_conflict(&["dir", "--preset", "archival", "--verify", "--no-verify"]);
+    }
+
+    #[test]
+    fn compatible_arguments_are_accepted() {
+        let args = parse(&["dir", "--delete", "--output", "a.cbz", "--webp-lossless"]).unwrap();
+        assert!(args.options.validate().is_ok());
+    }
 }
@@ -0,0 +1,252 @@
+//! Console output.
+//!
+//! Output for a directory is either printed immediately or buffered and printed at once, so that directories processed
+//! concurrently don't interleave their output. If the overall progress across all directories is shown, lines are
+//! printed above it and the progress bars of a directory are drawn below it. Output can also be captured instead, for
+//! callers of the library which present messages themselves.
+use std::{
+    fmt::Display,
+    io::{self, IsTerminal, Write},
+    sync::{Mutex, PoisonError},
+};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+
+/// Lock held while interacting with the user, so that concurrent prompts don't garble each other.
+static PROMPT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Stream a line is printed to.
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Kind of a message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Level {
+    Verbose,
+    Info,
+    Warning,
+    Error,
+}
+
+/// Amount of output printed.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Verbosity {
+    /// Only errors are printed.
+    Quiet,
+    Normal,
+    /// Additionally prints details about every decision made.
+    Verbose,
+}
+
+impl Verbosity {
+    /// Returns the verbosity selected by the `quiet` and `verbose` switches.
+    pub fn from_switches(quiet: bool, verbose: bool) -> Self {
+        if quiet {
+            Self::Quiet
+        } else if verbose {
+            Self::Verbose
+        } else {
+            Self::Normal
+        }
+    }
+}
+
+/// Console output of a single directory.
+pub struct Output {
+    buffered: bool,
+    verbosity: Verbosity,
+    lines: Vec<(Stream, String)>,
+    /// Overall progress drawn at the bottom of the terminal, if shown.
+    progress: Option<MultiProgress>,
+    /// Messages recorded instead of being printed, if captured.
+    messages: Option<Vec<(Level, String)>>,
+}
+
+impl Output {
+    /// Creates a new output, which holds back all lines until flushed if `buffered` is true.
+    pub fn new(buffered: bool, verbosity: Verbosity) -> Self {
+        Self {
+            buffered,
+            verbosity,
+            lines: Vec::new(),
+            progress: None,
+            messages: None,
+        }
+    }
+
+    /// Creates an output which records messages instead of printing them, to be retrieved using `messages`.
+    ///
+    /// Nothing is printed and no progress bars are drawn. Questions are recorded as warnings and answered with an empty
+    /// answer, which picks the default.
+    pub fn captured(verbosity: Verbosity) -> Self {
+        Self {
+            messages: Some(Vec::new()),
+            ..Self::new(false, verbosity)
+        }
+    }
+
+    /// Returns the messages recorded so far, which are always empty unless output is captured.
+    pub fn messages(&self) -> &[(Level, String)] {
+        self.messages.as_deref().unwrap_or_default()
+    }
+
+    /// Checks if questions can be asked, i.e. output is not captured and stdin is a terminal.
+    pub fn is_interactive(&self) -> bool {
+        self.messages.is_none() && io::stdin().is_terminal()
+    }
+
+    /// Prints lines above the overall progress `progress`, and draws progress bars below it.
+    pub fn with_progress(mut self, progress: MultiProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Runs `f` with the overall progress hidden, so that it can print to the terminal.
+    fn suspend<T>(&self, f: impl FnOnce() -> T) -> T {
+        match &self.progress {
+            Some(progress) => progress.suspend(f),
+            None => f(),
+        }
+    }
+
+    /// Returns true if progress bars should be shown.
+    ///
+    /// Progress bars are drawn directly to the terminal, so they are hidden if output is buffered, captured or quiet.
+    pub fn shows_progress(&self) -> bool {
+        !self.buffered && self.messages.is_none() && self.verbosity > Verbosity::Quiet
+    }
+
+    /// Sets up `bar` to be drawn below the overall progress, and hides it if progress bars are not shown.
+    ///
+    /// Returns `bar` for convenience.
+    pub fn add_progress(&self, bar: ProgressBar) -> ProgressBar {
+        if !self.shows_progress() {
+            bar.set_draw_target(ProgressDrawTarget::hidden());
+            return bar;
+        }
+        match &self.progress {
+            Some(progress) => progress.add(bar),
+            None => bar,
+        }
+    }
+
+    /// Finishes a progress bar set up using `add_progress`.
+    ///
+    /// Bars below the overall progress are cleared once finished, whereas other bars are left in place.
+    pub fn finish_progress(&self, bar: &ProgressBar) {
+        if !self.shows_progress() {
+            return;
+        }
+        match &self.progress {
+            Some(progress) => {
+                bar.finish_and_clear();
+                progress.remove(bar);
+            }
+            None => {
+                bar.finish();
+                eprintln!(); // TODO: indicatif has a bug where final newline is not printed. (https://github.com/console-rs/indicatif/issues/695)
+            }
+        }
+    }
+
+    /// Prints, buffers or records a message.
+    fn line(&mut self, level: Level, msg: String) {
+        if let Some(messages) = &mut self.messages {
+            messages.push((level, msg));
+            return;
+        }
+
+        let (stream, line) = match level {
+            Level::Verbose | Level::Info => (Stream::Stdout, msg),
+            Level::Warning => (
+                Stream::Stdout,
+                format!("{} {msg}", "[WARNING]".yellow().bold()),
+            ),
+            Level::Error => (Stream::Stderr, format!("{} {msg}", "[ERROR]".red().bold())),
+        };
+        if self.buffered {
+            self.lines.push((stream, line));
+        } else {
+            self.suspend(|| match stream {
+                Stream::Stdout => println!("{line}"),
+                Stream::Stderr => eprintln!("{line}"),
+            });
+        }
+    }
+
+    /// Prints a detailed message to stdout if output is verbose.
+    pub fn verbose(&mut self, msg: impl Display) {
+        if self.verbosity >= Verbosity::Verbose {
+            self.line(Level::Verbose, msg.to_string());
+        }
+    }
+
+    /// Prints an informational message to stdout unless output is quiet.
+    pub fn info(&mut self, msg: impl Display) {
+        if self.verbosity >= Verbosity::Normal {
+            self.line(Level::Info, msg.to_string());
+        }
+    }
+
+    /// Prints a warning to stdout unless output is quiet.
+    pub fn warn(&mut self, msg: impl Display) {
+        if self.verbosity < Verbosity::Normal {
+            return;
+        }
+        self.line(Level::Warning, msg.to_string());
+    }
+
+    /// Prints an error to stderr, regardless of verbosity.
+    pub fn error(&mut self, msg: impl Display) {
+        self.line(Level::Error, msg.to_string());
+    }
+
+    /// Prints any buffered lines, preceded by an empty line if `separate` is true.
+    pub fn flush(&mut self, separate: bool) {
+        let lines = std::mem::take(&mut self.lines);
+        self.suspend(|| {
+            let mut stdout = io::stdout().lock();
+            let mut stderr = io::stderr().lock();
+            if separate {
+                let _ = writeln!(stdout);
+            }
+            for (stream, line) in lines {
+                // Output is best effort, there is nothing sensible to do if the console is gone.
+                let _ = match stream {
+                    Stream::Stdout => writeln!(stdout, "{line}"),
+                    Stream::Stderr => writeln!(stderr, "{line}"),
+                };
+            }
+        });
+    }
+
+    /// Asks the user a question and returns the lowercased and trimmed answer.
+    ///
+    /// The question is a warning and is always printed immediately to stderr, after flushing any buffered lines so that
+    /// the user has some context. Printing to stderr keeps stdout free for machine-readable output. If output is
+    /// captured the question is recorded instead and answered with an empty answer. Errors are propagated with added
+    /// context.
+    pub fn prompt(&mut self, question: impl Display) -> Result<String> {
+        if let Some(messages) = &mut self.messages {
+            messages.push((Level::Warning, question.to_string()));
+            return Ok(String::new());
+        }
+
+        let _lock = PROMPT_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        self.flush(false);
+        self.suspend(|| {
+            eprint!("{} {question} ", "[WARNING]".yellow().bold());
+            io::stderr().flush().context("Failed to flush stderr")?;
+            let mut choice = String::new();
+            io::stdin()
+                .read_line(&mut choice)
+                .context("Failed to read user input")?;
+
+            Ok(choice.trim().to_lowercase())
+        })
+    }
+}
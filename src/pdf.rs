@@ -0,0 +1,213 @@
+//! Writing of PDF documents.
+//!
+//! Only what is needed to show one image per page is supported. Every image is placed on its own page, sized to the
+//! image at one point per pixel. JPEG images in grayscale or RGB are embedded as they are, all other images are
+//! decoded, flattened onto white and embedded losslessly compressed.
+use std::{
+    fmt::Write as _,
+    io::{self, Cursor, Write},
+};
+
+use anyhow::{Context, Result};
+use flate2::{Compression, write::ZlibEncoder};
+use image::{ColorType, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+
+/// Object number of the document catalog.
+const CATALOG: usize = 1;
+/// Object number of the page tree.
+const PAGES: usize = 2;
+
+/// Image data as embedded in a PDF.
+struct Embedded {
+    width: u32,
+    height: u32,
+    color_space: &'static str,
+    filter: &'static str,
+    data: Vec<u8>,
+}
+
+/// Prepares an image for embedding.
+///
+/// Errors are propagated.
+fn embed(buf: &[u8]) -> Result<Embedded> {
+    let format = image::guess_format(buf)?;
+    if format == ImageFormat::Jpeg {
+        let decoder = ImageReader::with_format(Cursor::new(buf), format).into_decoder()?;
+        let (width, height) = decoder.dimensions();
+        let color_space = match decoder.color_type() {
+            ColorType::L8 => Some("DeviceGray"),
+            ColorType::Rgb8 => Some("DeviceRGB"),
+            _ => None,
+        };
+        if let Some(color_space) = color_space {
+            return Ok(Embedded {
+                width,
+                height,
+                color_space,
+                filter: "DCTDecode",
+                data: buf.to_vec(),
+            });
+        }
+    }
+
+    let image = image::load_from_memory_with_format(buf, format)?;
+    let (width, height) = (image.width(), image.height());
+    let (color_space, pixels) = match image {
+        DynamicImage::ImageLuma8(image) => ("DeviceGray", image.into_raw()),
+        image => {
+            // PDF images have no alpha channel without a separate mask, so transparent pixels are blended with white.
+            let mut pixels = Vec::with_capacity(width as usize * height as usize * 3);
+            for pixel in image.to_rgba8().pixels() {
+                let [r, g, b, a] = pixel.0;
+                let blend = |c: u8| {
+                    let c = u16::from(c) * u16::from(a) + 255 * (255 - u16::from(a));
+                    u8::try_from(c / 255).unwrap_or(u8::MAX)
+                };
+                pixels.extend([blend(r), blend(g), blend(b)]);
+            }
+            ("DeviceRGB", pixels)
+        }
+    };
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&pixels)?;
+
+    Ok(Embedded {
+        width,
+        height,
+        color_space,
+        filter: "FlateDecode",
+        data: encoder.finish()?,
+    })
+}
+
+/// PDF document being written.
+pub struct PdfWriter<W> {
+    writer: W,
+    /// Number of bytes written so far.
+    offset: usize,
+    /// Byte offsets of all objects written so far, indexed by object number minus one.
+    offsets: Vec<usize>,
+    /// Object numbers of all pages written so far.
+    pages: Vec<usize>,
+}
+
+impl<W: Write> PdfWriter<W> {
+    /// Starts a new document by writing the header to `writer`.
+    ///
+    /// Errors are propagated.
+    pub fn new(writer: W) -> io::Result<Self> {
+        let mut pdf = Self {
+            writer,
+            offset: 0,
+            offsets: vec![0; PAGES],
+            pages: Vec::new(),
+        };
+        // The comment of non-ASCII bytes marks the file as binary.
+        pdf.write(b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n")?;
+
+        Ok(pdf)
+    }
+
+    /// Writes raw bytes, keeping track of the offset.
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)?;
+        self.offset += buf.len();
+        Ok(())
+    }
+
+    /// Reserves the number of a new object.
+    fn reserve(&mut self) -> usize {
+        self.offsets.push(0);
+        self.offsets.len()
+    }
+
+    /// Writes the object numbered `id`, consisting of `dict` followed by `stream` if provided.
+    fn object(&mut self, id: usize, dict: &str, stream: Option<&[u8]>) -> io::Result<()> {
+        self.offsets[id - 1] = self.offset;
+        self.write(format!("{id} 0 obj\n{dict}\n").as_bytes())?;
+        if let Some(stream) = stream {
+            self.write(b"stream\n")?;
+            self.write(stream)?;
+            self.write(b"\nendstream\n")?;
+        }
+        self.write(b"endobj\n")
+    }
+
+    /// Adds a page showing the image in `buf`.
+    ///
+    /// Fails if `buf` is not an image which can be decoded. Errors are propagated with added context.
+    pub fn add_image(&mut self, buf: &[u8]) -> Result<()> {
+        let embedded = embed(buf).context("Failed to embed image")?;
+        let (width, height) = (embedded.width, embedded.height);
+        let image = self.reserve();
+        self.object(
+            image,
+            &format!(
+                "<< /Type /XObject /Subtype /Image /Width {width} /Height {height} /ColorSpace /{} \
+                 /BitsPerComponent 8 /Filter /{} /Length {} >>",
+                embedded.color_space,
+                embedded.filter,
+                embedded.data.len()
+            ),
+            Some(&embedded.data),
+        )?;
+        let content = format!("q {width} 0 0 {height} 0 0 cm /Im0 Do Q");
+        let contents = self.reserve();
+        self.object(
+            contents,
+            &format!("<< /Length {} >>", content.len()),
+            Some(content.as_bytes()),
+        )?;
+        let page = self.reserve();
+        self.object(
+            page,
+            &format!(
+                "<< /Type /Page /Parent {PAGES} 0 R /MediaBox [0 0 {width} {height}] \
+                 /Resources << /XObject << /Im0 {image} 0 R >> >> /Contents {contents} 0 R >>"
+            ),
+            None,
+        )?;
+        self.pages.push(page);
+
+        Ok(())
+    }
+
+    /// Finishes the document by writing the page tree, catalog and cross-reference table.
+    ///
+    /// Returns the underlying writer. Errors are propagated.
+    pub fn finish(mut self) -> io::Result<W> {
+        let kids = self
+            .pages
+            .iter()
+            .map(|page| format!("{page} 0 R"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let count = self.pages.len();
+        self.object(
+            PAGES,
+            &format!("<< /Type /Pages /Kids [{kids}] /Count {count} >>"),
+            None,
+        )?;
+        self.object(
+            CATALOG,
+            &format!("<< /Type /Catalog /Pages {PAGES} 0 R >>"),
+            None,
+        )?;
+
+        let xref = self.offset;
+        let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        // Writing to a String never fails.
+        for offset in &self.offsets {
+            let _ = writeln!(table, "{offset:010} 00000 n ");
+        }
+        let _ = write!(
+            table,
+            "trailer\n<< /Size {} /Root {CATALOG} 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            self.offsets.len() + 1
+        );
+        self.write(table.as_bytes())?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
@@ -0,0 +1,136 @@
+//! Machine-readable summary of processed directories.
+//!
+//! The summary is printed as a single JSON object. Any change to its structure must increment `SCHEMA_VERSION`.
+use std::path::Path;
+
+use serde::Serialize;
+
+/// Version of the summary schema.
+pub const SCHEMA_VERSION: u32 = 5;
+
+/// Outcome of processing a directory.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The archive was written.
+    Created,
+    /// The planned actions were printed, but nothing was written.
+    DryRun,
+    /// Nothing was written, because the user declined to overwrite, the output was up to date or non-images were found.
+    #[default]
+    Skipped,
+    /// An error occurred.
+    Failed,
+    /// The images were renamed in place, without writing an archive.
+    Renamed,
+    /// The details of the files were listed, without writing an archive.
+    Inspected,
+}
+
+impl Status {
+    /// Returns the name of the status, as used in the JSON summary.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::DryRun => "dry_run",
+            Self::Skipped => "skipped",
+            Self::Failed => "failed",
+            Self::Renamed => "renamed",
+            Self::Inspected => "inspected",
+        }
+    }
+}
+
+/// Summary of a single directory.
+#[derive(Serialize, Debug, Default)]
+pub struct Summary {
+    pub dir: String,
+    pub status: Status,
+    /// Path of the archive, or of its first part if split.
+    pub output: Option<String>,
+    /// Number of parts the archive was split into, which is one unless splitting.
+    pub parts: usize,
+    pub pages: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Ratio of `bytes_out` to `bytes_in`, if any input was read.
+    pub ratio: Option<f64>,
+    pub excluded: Vec<String>,
+    pub non_images: Vec<String>,
+    pub errors: Vec<String>,
+    /// Details of every file, which are only gathered when inspecting.
+    pub files: Vec<FileDetails>,
+}
+
+/// Details of a single file, as listed when inspecting a directory.
+#[derive(Serialize, Debug)]
+pub struct FileDetails {
+    pub path: String,
+    /// Detected image format, if any.
+    pub format: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub color_type: Option<String>,
+    /// Number of bits per channel.
+    pub bit_depth: Option<u16>,
+    pub size: Option<u64>,
+    /// Whether the image data could be decoded.
+    pub decoded: bool,
+    /// Why the file is not packed as an image, or else why it failed to decode.
+    pub reason: Option<String>,
+}
+
+impl Summary {
+    /// Creates an empty summary for `dir`.
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.display().to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Records the size of the written archive, along with its ratio to the size of the input.
+    #[expect(
+        clippy::cast_precision_loss,
+        reason = "The ratio only needs to be approximate"
+    )]
+    pub fn set_bytes_out(&mut self, bytes_out: u64) {
+        self.bytes_out = bytes_out;
+        self.ratio = (self.bytes_in > 0).then(|| bytes_out as f64 / self.bytes_in as f64);
+    }
+}
+
+/// Formats a number of bytes using binary units, e.g. `1.5 MiB`.
+#[expect(
+    clippy::cast_precision_loss,
+    reason = "Sizes are only printed approximately"
+)]
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Serializes the summaries of all directories to JSON.
+pub fn to_json(summaries: &[Summary]) -> String {
+    #[derive(Serialize)]
+    struct Report<'a> {
+        schema_version: u32,
+        directories: &'a [Summary],
+    }
+
+    // Serializing plain structs with string keys never fails.
+    serde_json::to_string_pretty(&Report {
+        schema_version: SCHEMA_VERSION,
+        directories: summaries,
+    })
+    .unwrap_or_default()
+}
@@ -0,0 +1,177 @@
+//! Templates for naming archive entries.
+//!
+//! A template is literal text with placeholders in braces, e.g. `page_{n:04}.{ext}`. Templates are validated when
+//! parsed, so that invalid templates are rejected before any directory is processed.
+use std::{fmt::Write, str::FromStr};
+
+use anyhow::Result;
+
+/// Part of a template.
+#[derive(Clone, Debug)]
+enum Token {
+    Literal(String),
+    /// Page number, zero-padded to the given width or to the default width.
+    Number(Option<usize>),
+    /// Original file stem.
+    Orig,
+    /// Extension of the format the page is written in.
+    Ext,
+    /// Series of the comic.
+    Series,
+}
+
+/// Parsed name template.
+#[derive(Clone, Debug)]
+pub struct NameTemplate {
+    tokens: Vec<Token>,
+}
+
+/// Values substituted for the placeholders of a template.
+pub struct Values<'a> {
+    pub number: usize,
+    /// Width page numbers are padded to unless the template specifies one.
+    pub width: usize,
+    pub orig: &'a str,
+    pub ext: &'a str,
+    pub series: Option<&'a str>,
+}
+
+impl Default for NameTemplate {
+    /// Returns the template `{n}.{ext}`, which names pages by their number.
+    fn default() -> Self {
+        Self {
+            tokens: vec![
+                Token::Number(None),
+                Token::Literal(".".to_string()),
+                Token::Ext,
+            ],
+        }
+    }
+}
+
+impl NameTemplate {
+    /// Checks whether the template contains a `{series}` placeholder.
+    pub fn uses_series(&self) -> bool {
+        self.tokens
+            .iter()
+            .any(|token| matches!(token, Token::Series))
+    }
+
+    /// Expands the template using `values`.
+    ///
+    /// A `{series}` placeholder expands to nothing if no series is provided, so callers should check `uses_series`
+    /// first. Path separators in the series are replaced by underscores, so that `AC/DC` can't nest pages in
+    /// directories.
+    pub fn expand(&self, values: &Values) -> String {
+        let mut name = String::new();
+        for token in &self.tokens {
+            match token {
+                Token::Literal(text) => name.push_str(text),
+                Token::Number(width) => {
+                    // Writing to a String never fails.
+                    let _ = write!(
+                        name,
+                        "{:0width$}",
+                        values.number,
+                        width = width.unwrap_or(values.width)
+                    );
+                }
+                Token::Orig => name.push_str(values.orig),
+                Token::Ext => name.push_str(values.ext),
+                Token::Series => name.extend(
+                    values
+                        .series
+                        .unwrap_or_default()
+                        .chars()
+                        .map(|c| if matches!(c, '/' | '\\') { '_' } else { c }),
+                ),
+            }
+        }
+        name
+    }
+}
+
+/// Parses a placeholder, given without its braces.
+fn parse_placeholder(placeholder: &str) -> Result<Token> {
+    let token = match placeholder {
+        "n" => Token::Number(None),
+        "orig" => Token::Orig,
+        "ext" => Token::Ext,
+        "series" => Token::Series,
+        _ => {
+            // Widths are given as `0N` or `0Nd`, e.g. `{n:04}`.
+            let width = placeholder
+                .strip_prefix("n:0")
+                .map(|spec| spec.strip_suffix('d').unwrap_or(spec))
+                .and_then(|width| width.parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("Unknown placeholder {{{placeholder}}}"))?;
+            Token::Number(Some(width))
+        }
+    };
+    Ok(token)
+}
+
+impl FromStr for NameTemplate {
+    type Err = anyhow::Error;
+
+    /// Parses a template.
+    ///
+    /// Fails on unknown placeholders, unbalanced braces and path separators, and if the template lacks an `{ext}`
+    /// placeholder, as pages have to keep the extension of their format for readers to display them.
+    fn from_str(source: &str) -> Result<Self> {
+        let mut tokens = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let mut placeholder = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        placeholder.push(c);
+                    }
+                    if !closed {
+                        anyhow::bail!("Unclosed placeholder {{{placeholder} in name template");
+                    }
+                    if !literal.is_empty() {
+                        tokens.push(Token::Literal(std::mem::take(&mut literal)));
+                    }
+                    tokens.push(parse_placeholder(&placeholder)?);
+                }
+                '}' => anyhow::bail!("Unmatched }} in name template"),
+                '/' | '\\' => anyhow::bail!("Name template must not contain path separators"),
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+        if !tokens.iter().any(|token| matches!(token, Token::Ext)) {
+            anyhow::bail!("Name template must contain an {{ext}} placeholder");
+        }
+
+        Ok(Self { tokens })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn series_can_not_add_directories() {
+        let template: NameTemplate = "{series} {n}.{ext}".parse().unwrap();
+        let name = template.expand(&Values {
+            number: 1,
+            width: 2,
+            orig: "",
+            ext: "png",
+            series: Some("../AC/DC\\x"),
+        });
+        assert_eq!(name, ".._AC_DC_x 01.png");
+    }
+}